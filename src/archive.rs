@@ -0,0 +1,570 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::{ArchiveTarget, Config};
+use crate::s3::{self, S3Client};
+use crate::search;
+use crate::{Log, LEDGER_LOCK};
+
+/// zstd compression level used for day archives.
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Where a day is archived. Archives are always built locally first; remote
+/// targets upload them and keep a receipt next to where the archive would be.
+#[derive(Clone)]
+pub enum ArchiveStore {
+    Local,
+    S3(S3Client),
+}
+
+impl ArchiveStore {
+    pub fn from_config(config: &Config) -> ArchiveStore {
+        match config.archive_target {
+            ArchiveTarget::Local => ArchiveStore::Local,
+            ArchiveTarget::S3 => ArchiveStore::S3(S3Client::new(config.s3.clone())),
+        }
+    }
+}
+
+/// Written to `<app>/restored/<date>.json` when a day is restored so retention
+/// gives it a fresh window instead of archiving it again on the next run.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RestoreMarker {
+    pub restored_at: String,
+    /// Size of the ledger right after the restore.
+    pub ledger_size: u64,
+    /// Whether the ledger held nothing but restored entries. When it still does
+    /// at expiry, its size unchanged, the archives already hold every entry and
+    /// the live copy can simply be dropped.
+    #[serde(default)]
+    pub only_restored: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RestoredDay {
+    pub date: String,
+    pub archives: usize,
+    pub restored: usize,
+    pub skipped: usize,
+}
+
+/// Written to `<archive_path>/<app>/<name>.s3.json` once an archive has been
+/// uploaded and verified.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct S3Receipt {
+    pub bucket: String,
+    pub key: String,
+    pub size: u64,
+    pub md5: String,
+    pub sha256: String,
+    pub archived_at: String,
+}
+
+/// Archives an app's ledger and search cache for a single day, then removes the
+/// originals.
+///
+/// The archive contains `ledgers/<date>.jsonl` and `search/<date>/<uid>` using
+/// the same layout as the live storage directory. If the day was archived before
+/// (for example when late entries arrived after the first run) a numbered archive
+/// is written next to the existing one instead of replacing it. Entries appended
+/// while the archive is built or uploaded stay in the live ledger for the next run.
+pub async fn archive_day(
+    store: &ArchiveStore,
+    app_path: &Path,
+    archive_path: &Path,
+    date: &str,
+) -> Result<()> {
+    let app = app_path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid app path {}", app_path.display()))?
+        .to_string_lossy()
+        .to_string();
+    let app_archive_path = archive_path.join(&app);
+    fs::create_dir_all(&app_archive_path)?;
+    let name = next_archive_name(&app_archive_path, date);
+    let archive = app_archive_path.join(format!("{}.tar.zst", name));
+    let tmp = app_archive_path.join(format!("{}.tar.zst.tmp", name));
+
+    let archived_length = {
+        let app_path = app_path.to_owned();
+        let tmp = tmp.clone();
+        let date = date.to_string();
+        tokio::task::spawn_blocking(move || build_archive(&app_path, &date, &tmp)).await??
+    };
+
+    let result = match store {
+        ArchiveStore::Local => fs::rename(&tmp, &archive)
+            .with_context(|| format!("Failed to move archive into {}", archive.display())),
+        ArchiveStore::S3(client) => upload(client, &tmp, &app, &name, &app_archive_path).await,
+    };
+    if let Err(error) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(error);
+    }
+
+    remove_archived(app_path, date, archived_length)
+}
+
+/// Removes the first `length` bytes of a day's ledger, the part that was
+/// archived, and the whole day when nothing was appended since.
+fn remove_archived(app_path: &Path, date: &str, length: u64) -> Result<()> {
+    let _ledger_lock = LEDGER_LOCK.lock().unwrap();
+    let ledger = app_path.join("ledgers").join(format!("{}.jsonl", date));
+    let contents = fs::read(&ledger)?;
+    if contents.len() as u64 <= length {
+        return remove_day(app_path, date);
+    }
+
+    let (archived, late) = contents.split_at(length as usize);
+    let uids: Vec<String> = String::from_utf8_lossy(archived)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Log>(line).ok())
+        .map(|log| log.uid)
+        .collect();
+    let tmp = ledger.with_extension("jsonl.tmp");
+    fs::write(&tmp, late)?;
+    fs::rename(&tmp, &ledger)?;
+    search::delete(app_path, date, &uids)?;
+    let search_cache = app_path.join("search").join(date);
+    for uid in uids.iter() {
+        let _ = fs::remove_file(search_cache.join(uid));
+    }
+    Ok(())
+}
+
+/// Uploads a built archive, verifies the stored object matches the local
+/// checksums and records a receipt. The local archive is removed afterwards.
+async fn upload(
+    client: &S3Client,
+    file: &Path,
+    app: &str,
+    name: &str,
+    app_archive_path: &Path,
+) -> Result<()> {
+    let checksums = {
+        let file = file.to_owned();
+        tokio::task::spawn_blocking(move || s3::checksums(&file)).await??
+    };
+    let key = client.key(&format!("{}/{}.tar.zst", app, name));
+    client.put_file(&key, file, &checksums).await?;
+
+    let object = client.head(&key).await?;
+    if object.size != checksums.size {
+        return Err(anyhow!(
+            "Uploaded object {} is {} bytes, expected {}",
+            key,
+            object.size,
+            checksums.size
+        ));
+    }
+    if !object.etag.eq_ignore_ascii_case(&checksums.md5) {
+        return Err(anyhow!(
+            "Uploaded object {} has checksum {}, expected {}",
+            key,
+            object.etag,
+            checksums.md5
+        ));
+    }
+
+    let receipt = S3Receipt {
+        bucket: client.bucket().to_string(),
+        key,
+        size: checksums.size,
+        md5: checksums.md5,
+        sha256: checksums.sha256,
+        archived_at: chrono::Utc::now().to_rfc3339(),
+    };
+    fs::write(
+        app_archive_path.join(format!("{}.s3.json", name)),
+        serde_json::to_string_pretty(&receipt)?,
+    )?;
+    fs::remove_file(file)?;
+    Ok(())
+}
+
+/// Writes `ledgers/<date>.jsonl`, and the `search/<date>/` cache of days written
+/// before the search index, into a zstd compressed tar. Returns the length of
+/// the ledger that was archived.
+fn build_archive(app_path: &Path, date: &str, destination: &Path) -> Result<u64> {
+    let ledger = app_path.join("ledgers").join(format!("{}.jsonl", date));
+    let search_cache = app_path.join("search").join(date);
+    if !ledger.exists() {
+        return Err(anyhow!("No ledger found for {}", date));
+    }
+
+    let file = fs::File::create(destination)?;
+    let encoder = zstd::Encoder::new(file, COMPRESSION_LEVEL)?;
+    let mut builder = tar::Builder::new(encoder);
+    // Entries are appended whole under the ledger lock, so the ledger up to
+    // its current length is a complete snapshot even while it keeps growing.
+    let length = {
+        let _ledger_lock = LEDGER_LOCK.lock().unwrap();
+        fs::metadata(&ledger)?.len()
+    };
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata(&fs::metadata(&ledger)?);
+    header.set_size(length);
+    builder.append_data(
+        &mut header,
+        format!("ledgers/{}.jsonl", date),
+        fs::File::open(&ledger)?.take(length),
+    )?;
+    if search_cache.exists() {
+        builder.append_dir_all(format!("search/{}", date), &search_cache)?;
+    }
+    let encoder = builder.into_inner()?;
+    encoder.finish()?.sync_all()?;
+    Ok(length)
+}
+
+/// Restores every archived day of an app between `from` and `to` (inclusive)
+/// back into live storage. Entries already present in the live ledger are
+/// skipped so restoring twice is harmless.
+pub async fn restore_days(
+    store: &ArchiveStore,
+    app_path: &Path,
+    archive_path: &Path,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<Vec<RestoredDay>> {
+    if to < from {
+        return Err(anyhow!("The end of the range must not be before the start"));
+    }
+    let app = app_path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid app path {}", app_path.display()))?
+        .to_string_lossy()
+        .to_string();
+
+    let mut days: Vec<(String, Vec<PathBuf>)> = Vec::new();
+    for (date, path) in archives(archive_path, &app) {
+        let Ok(day) = NaiveDate::parse_from_str(&date, "%Y-%m-%d") else {
+            continue;
+        };
+        if day < from || day > to {
+            continue;
+        }
+        match days.last_mut() {
+            Some((last, paths)) if *last == date => paths.push(path),
+            _ => days.push((date, vec![path])),
+        }
+    }
+    if days.is_empty() {
+        return Err(anyhow!(
+            "No archives found for {} between {} and {}",
+            app,
+            from,
+            to
+        ));
+    }
+
+    let mut restored = Vec::new();
+    for (date, paths) in days {
+        let mut downloads = Vec::new();
+        let mut files = Vec::new();
+        for path in paths.iter() {
+            if path.to_string_lossy().ends_with(".s3.json") {
+                let download = download(store, path).await?;
+                files.push(download.clone());
+                downloads.push(download);
+            } else {
+                files.push(path.clone());
+            }
+        }
+
+        let result = {
+            let app_path = app_path.to_owned();
+            let date = date.clone();
+            tokio::task::spawn_blocking(move || restore_day(&app_path, &date, &files)).await?
+        };
+        for download in downloads {
+            let _ = fs::remove_file(download);
+        }
+        let (restored_entries, skipped) = result?;
+
+        restored.push(RestoredDay {
+            date,
+            archives: paths.len(),
+            restored: restored_entries,
+            skipped,
+        });
+    }
+
+    Ok(restored)
+}
+
+/// Fetches the archive described by an S3 receipt into a temporary file next to
+/// the receipt and checks it against the recorded SHA-256.
+async fn download(store: &ArchiveStore, receipt_path: &Path) -> Result<PathBuf> {
+    let ArchiveStore::S3(client) = store else {
+        return Err(anyhow!(
+            "{} was archived to S3 but the archive target is not s3",
+            receipt_path.display()
+        ));
+    };
+    let receipt: S3Receipt = serde_json::from_str(&fs::read_to_string(receipt_path)?)?;
+    let destination = receipt_path.with_extension("download.tmp");
+    client.get_file(&receipt.key, &destination).await?;
+
+    let checksums = {
+        let destination = destination.clone();
+        tokio::task::spawn_blocking(move || s3::checksums(&destination)).await??
+    };
+    if checksums.sha256 != receipt.sha256 {
+        let _ = fs::remove_file(&destination);
+        return Err(anyhow!(
+            "Downloaded {} does not match its recorded checksum",
+            receipt.key
+        ));
+    }
+    Ok(destination)
+}
+
+/// Appends the ledger entries of the archives to the live ledger, indexes them
+/// for search and writes the restore marker. Returns the number of restored
+/// and skipped (already present) entries.
+fn restore_day(app_path: &Path, date: &str, archives: &[PathBuf]) -> Result<(usize, usize)> {
+    let _ledger_lock = LEDGER_LOCK.lock().unwrap();
+    let ledger_path = app_path.join("ledgers").join(format!("{}.jsonl", date));
+    fs::create_dir_all(app_path.join("ledgers"))?;
+
+    // Entries that were live before the restore may never have been archived.
+    let live_size = fs::metadata(&ledger_path).map_or(0, |metadata| metadata.len());
+    let only_restored = live_size == 0
+        || restore_marker(app_path, date)
+            .is_some_and(|marker| marker.only_restored && marker.ledger_size == live_size);
+
+    let mut uids: HashSet<String> = HashSet::new();
+    if ledger_path.exists() {
+        for line in fs::read_to_string(&ledger_path)?.lines() {
+            if let Some(uid) = serde_json::from_str::<serde_json::Value>(line)
+                .ok()
+                .and_then(|log| log["uid"].as_str().map(|uid| uid.to_string()))
+            {
+                uids.insert(uid);
+            }
+        }
+    }
+
+    let mut ledger = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&ledger_path)?;
+    let mut restored: Vec<Log> = Vec::new();
+    let mut skipped = 0;
+    for archive in archives {
+        let contents = read_ledger(archive, date)
+            .with_context(|| format!("Failed to read {}", archive.display()))?;
+        for line in contents.lines() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let log: Log = serde_json::from_str(line)
+                .map_err(|e| anyhow!("Archived log is malformed: {}", e))?;
+            if !uids.insert(log.uid.clone()) {
+                skipped += 1;
+                continue;
+            }
+            ledger.write_all(line.as_bytes())?;
+            ledger.write_all("\n".as_bytes())?;
+            restored.push(log);
+        }
+    }
+    ledger.sync_all()?;
+    search::add(app_path, date, &restored.iter().collect::<Vec<&Log>>())?;
+
+    let marker = RestoreMarker {
+        restored_at: chrono::Utc::now().to_rfc3339(),
+        ledger_size: fs::metadata(&ledger_path)?.len(),
+        only_restored,
+    };
+    fs::create_dir_all(app_path.join("restored"))?;
+    fs::write(
+        restore_marker_path(app_path, date),
+        serde_json::to_string(&marker)?,
+    )?;
+
+    Ok((restored.len(), skipped))
+}
+
+/// Reads `ledgers/<date>.jsonl` out of a day archive.
+fn read_ledger(archive: &Path, date: &str) -> Result<String> {
+    let file = fs::File::open(archive)?;
+    let decoder = zstd::Decoder::new(file)?;
+    let mut tar = tar::Archive::new(decoder);
+    let name = format!("ledgers/{}.jsonl", date);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.to_string_lossy() == name {
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents)?;
+            return Ok(contents);
+        }
+    }
+    Err(anyhow!("Archive does not contain {}", name))
+}
+
+pub fn restore_marker_path(app_path: &Path, date: &str) -> PathBuf {
+    app_path.join("restored").join(format!("{}.json", date))
+}
+
+pub fn restore_marker(app_path: &Path, date: &str) -> Option<RestoreMarker> {
+    let contents = fs::read_to_string(restore_marker_path(app_path, date)).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+pub fn remove_day(app_path: &Path, date: &str) -> Result<()> {
+    fs::remove_file(app_path.join("ledgers").join(format!("{}.jsonl", date)))?;
+    let search_cache = app_path.join("search").join(date);
+    if search_cache.exists() {
+        fs::remove_dir_all(&search_cache)?;
+    }
+    search::remove(app_path, date)?;
+    let marker = restore_marker_path(app_path, date);
+    if marker.exists() {
+        fs::remove_file(marker)?;
+    }
+    Ok(())
+}
+
+/// Lists the dates that have at least one archive for the app, sorted ascending.
+pub fn archived_days(archive_path: &Path, app: &str) -> Vec<String> {
+    let mut days: Vec<String> = archives(archive_path, app)
+        .into_iter()
+        .map(|(date, _)| date)
+        .collect();
+    days.dedup();
+    days
+}
+
+/// Every local archive or S3 receipt for the app paired with the date it
+/// contains, sorted by date.
+pub fn archives(archive_path: &Path, app: &str) -> Vec<(String, PathBuf)> {
+    let Ok(entries) = fs::read_dir(archive_path.join(app)) else {
+        return Vec::new();
+    };
+    let mut archives: Vec<(String, PathBuf)> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter_map(|path| {
+            let name = path.file_name()?.to_str()?;
+            if !name.ends_with(".tar.zst") && !name.ends_with(".s3.json") {
+                return None;
+            }
+            let date = name.split('.').next()?.to_string();
+            Some((date, path))
+        })
+        .collect();
+    archives.sort();
+    archives
+}
+
+fn next_archive_name(app_archive_path: &Path, date: &str) -> String {
+    let taken = |name: &str| {
+        app_archive_path.join(format!("{}.tar.zst", name)).exists()
+            || app_archive_path.join(format!("{}.s3.json", name)).exists()
+    };
+    if !taken(date) {
+        return date.to_string();
+    }
+    let mut i = 1;
+    loop {
+        let name = format!("{}.{}", date, i);
+        if !taken(&name) {
+            return name;
+        }
+        i += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::empty_log;
+
+    fn ledger_line(message: &str) -> String {
+        let mut log = empty_log();
+        log.message = message.to_string();
+        serde_json::to_string(&log).unwrap() + "\n"
+    }
+
+    #[test]
+    fn keeps_entries_appended_after_the_snapshot() {
+        let app_path =
+            std::env::temp_dir().join(format!("lumberjack-archive-{}", uuid::Uuid::now_v7()));
+        let ledger = app_path.join("ledgers").join("2024-04-06.jsonl");
+        fs::create_dir_all(app_path.join("ledgers")).unwrap();
+        let archived_line = ledger_line("archived");
+        fs::write(&ledger, &archived_line).unwrap();
+
+        let archive = app_path.join("2024-04-06.tar.zst");
+        let length = build_archive(&app_path, "2024-04-06", &archive).unwrap();
+        let late = ledger_line("late");
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&ledger)
+            .unwrap()
+            .write_all(late.as_bytes())
+            .unwrap();
+
+        remove_archived(&app_path, "2024-04-06", length).unwrap();
+        assert_eq!(fs::read_to_string(&ledger).unwrap(), late);
+
+        let mut archived = String::new();
+        let mut entries =
+            tar::Archive::new(zstd::Decoder::new(fs::File::open(&archive).unwrap()).unwrap());
+        entries
+            .entries()
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .read_to_string(&mut archived)
+            .unwrap();
+        assert_eq!(archived, archived_line);
+
+        remove_archived(&app_path, "2024-04-06", late.len() as u64).unwrap();
+        assert!(!ledger.exists());
+
+        fs::remove_dir_all(&app_path).unwrap();
+    }
+
+    #[test]
+    fn marks_whether_a_restored_day_had_live_entries() {
+        let app_path =
+            std::env::temp_dir().join(format!("lumberjack-archive-{}", uuid::Uuid::now_v7()));
+        let ledger = app_path.join("ledgers").join("2024-04-06.jsonl");
+        fs::create_dir_all(app_path.join("ledgers")).unwrap();
+        fs::write(&ledger, ledger_line("archived")).unwrap();
+        let archive = app_path.join("2024-04-06.tar.zst");
+        build_archive(&app_path, "2024-04-06", &archive).unwrap();
+        fs::remove_file(&ledger).unwrap();
+
+        restore_day(&app_path, "2024-04-06", std::slice::from_ref(&archive)).unwrap();
+        assert!(
+            restore_marker(&app_path, "2024-04-06")
+                .unwrap()
+                .only_restored
+        );
+        // Restoring again over an untouched restore changes nothing.
+        restore_day(&app_path, "2024-04-06", std::slice::from_ref(&archive)).unwrap();
+        assert!(
+            restore_marker(&app_path, "2024-04-06")
+                .unwrap()
+                .only_restored
+        );
+
+        fs::write(&ledger, ledger_line("live")).unwrap();
+        restore_day(&app_path, "2024-04-06", std::slice::from_ref(&archive)).unwrap();
+        let marker = restore_marker(&app_path, "2024-04-06").unwrap();
+        assert!(!marker.only_restored);
+        assert_eq!(marker.ledger_size, fs::metadata(&ledger).unwrap().len());
+
+        fs::remove_dir_all(&app_path).unwrap();
+    }
+}
@@ -0,0 +1,853 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::NaiveDate;
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::ErrorLevel;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub enum Rentention {
+    DELETE,
+    ARCHIVE,
+}
+
+impl Display for Rentention {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Rentention::DELETE => write!(f, "DELETE"),
+            Rentention::ARCHIVE => write!(f, "ARCHIVE"),
+        }
+    }
+}
+
+impl FromStr for Rentention {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "delete" => Ok(Rentention::DELETE),
+            "archive" => Ok(Rentention::ARCHIVE),
+            _ => Err(anyhow!(
+                "invalid retention mode \"{}\" (expected \"delete\" or \"archive\")",
+                s
+            )),
+        }
+    }
+}
+
+/// Where archived days are stored when the retention mode is ARCHIVE.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ArchiveTarget {
+    Local,
+    S3,
+}
+
+impl Display for ArchiveTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ArchiveTarget::Local => write!(f, "local"),
+            ArchiveTarget::S3 => write!(f, "s3"),
+        }
+    }
+}
+
+impl FromStr for ArchiveTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "local" => Ok(ArchiveTarget::Local),
+            "s3" => Ok(ArchiveTarget::S3),
+            _ => Err(anyhow!(
+                "invalid archive target \"{}\" (expected \"local\" or \"s3\")",
+                s
+            )),
+        }
+    }
+}
+
+/// Transport a syslog or GELF listener accepts messages on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ListenerProtocol {
+    Udp,
+    Tcp,
+}
+
+impl Display for ListenerProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ListenerProtocol::Udp => write!(f, "udp"),
+            ListenerProtocol::Tcp => write!(f, "tcp"),
+        }
+    }
+}
+
+impl FromStr for ListenerProtocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "udp" => Ok(ListenerProtocol::Udp),
+            "tcp" => Ok(ListenerProtocol::Tcp),
+            _ => Err(anyhow!(
+                "invalid listener protocol \"{}\" (expected \"udp\" or \"tcp\")",
+                s
+            )),
+        }
+    }
+}
+
+/// Runtime configuration.
+///
+/// Values are resolved with the following precedence (highest first):
+/// CLI flags, environment variables (including a `.env` file in the working
+/// directory), the TOML config file, and finally the built-in defaults.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub storage_path: String,
+    pub master_key: String,
+    pub port: u16,
+    /// Largest request body accepted by `POST /logs`, in bytes.
+    pub max_request_size: u64,
+    /// Largest single log entry accepted by `POST /logs`, in bytes.
+    pub max_entry_size: usize,
+    /// Hours a shipment's `Idempotency-Key` is remembered, 0 ignores the header.
+    pub idempotency_window: u64,
+    /// Per app level names, uppercased, mapped onto levels when ingesting.
+    pub level_aliases: HashMap<String, HashMap<String, ErrorLevel>>,
+    pub mode: Rentention,
+    pub days_retained: u32,
+    /// Minutes between scheduled retention runs, 0 disables the scheduler.
+    pub retention_interval: u64,
+    /// Per app, level and env overrides of `days_retained`, checked in order.
+    pub retention: Vec<RetentionPolicy>,
+    pub archive_path: String,
+    pub archive_target: ArchiveTarget,
+    pub s3: S3Config,
+    pub discord: WebhookConfig,
+    pub slack: WebhookConfig,
+    pub teams: WebhookConfig,
+    pub syslog: Vec<Listener>,
+    pub gelf: Vec<Listener>,
+}
+
+/// Overrides how long matching logs are retained. Empty filters match anything.
+#[derive(Clone, Debug)]
+pub struct RetentionPolicy {
+    pub app: Option<String>,
+    pub levels: Vec<ErrorLevel>,
+    pub envs: Vec<String>,
+    pub days: u32,
+}
+
+impl RetentionPolicy {
+    pub fn applies_to_app(&self, app: &str) -> bool {
+        self.app.as_ref().is_none_or(|policy_app| policy_app == app)
+    }
+
+    pub fn matches(&self, app: &str, level: ErrorLevel, env: &str) -> bool {
+        self.applies_to_app(app)
+            && (self.levels.is_empty() || self.levels.contains(&level))
+            && (self.envs.is_empty() || self.envs.iter().any(|e| e.eq_ignore_ascii_case(env)))
+    }
+}
+
+/// A syslog or GELF listener. Neither protocol has an `Authorization` header,
+/// so every listener lists the apps its messages may be written to instead.
+#[derive(Clone, Debug)]
+pub struct Listener {
+    pub protocol: ListenerProtocol,
+    pub bind: String,
+    /// Kebab-cased apps messages may be written to, `*` allows any app.
+    pub apps: Vec<String>,
+    /// Writes every message to this app instead of the one named by the message.
+    pub app: Option<String>,
+    pub env: String,
+}
+
+impl Listener {
+    pub fn allows(&self, app: &str) -> bool {
+        self.apps
+            .iter()
+            .any(|allowed| allowed == "*" || allowed == app)
+    }
+}
+
+/// Connection details for an S3-compatible object store.
+#[derive(Clone, Debug)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub bucket: String,
+    pub prefix: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Address buckets as `<endpoint>/<bucket>` instead of `<bucket>.<endpoint>`.
+    /// Required by MinIO and most self-hosted stores.
+    pub path_style: bool,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        S3Config {
+            endpoint: "".to_string(),
+            bucket: "".to_string(),
+            prefix: "".to_string(),
+            region: "us-east-1".to_string(),
+            access_key: "".to_string(),
+            secret_key: "".to_string(),
+            path_style: true,
+        }
+    }
+}
+
+/// A chat webhook and the log levels that should be forwarded to it. An empty
+/// `url` disables the webhook.
+#[derive(Clone, Debug, Default)]
+pub struct WebhookConfig {
+    pub url: String,
+    pub levels: Vec<ErrorLevel>,
+}
+
+impl WebhookConfig {
+    pub fn sends(&self, level: ErrorLevel) -> bool {
+        !self.url.is_empty() && self.levels.contains(&level)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            storage_path: "./data".to_string(),
+            master_key: "".to_string(),
+            port: 7777,
+            max_request_size: 32 * 1024 * 1024,
+            max_entry_size: 1024 * 1024,
+            idempotency_window: 24,
+            level_aliases: HashMap::new(),
+            mode: Rentention::DELETE,
+            days_retained: 14,
+            retention_interval: 60,
+            retention: Vec::new(),
+            archive_path: "./archive".to_string(),
+            archive_target: ArchiveTarget::Local,
+            s3: S3Config::default(),
+            discord: WebhookConfig::default(),
+            slack: WebhookConfig::default(),
+            teams: WebhookConfig::default(),
+            syslog: Vec::new(),
+            gelf: Vec::new(),
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about = "A free and open source log aggregator")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+
+    /// Path to a TOML config file
+    #[arg(long, env = "LUMBERJACK_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Directory where app ledgers and keychains are stored
+    #[arg(long, env = "STORAGE_PATH")]
+    pub storage_path: Option<String>,
+
+    /// Master authorization key
+    #[arg(long, env = "MASTER_KEY", hide_env_values = true)]
+    pub master_key: Option<String>,
+
+    /// Port the HTTP server listens on
+    #[arg(long, env = "PORT")]
+    pub port: Option<u16>,
+
+    /// Largest request body accepted when ingesting logs, in bytes
+    #[arg(long, env = "MAX_REQUEST_SIZE")]
+    pub max_request_size: Option<u64>,
+
+    /// Largest single log entry accepted when ingesting logs, in bytes
+    #[arg(long, env = "MAX_ENTRY_SIZE")]
+    pub max_entry_size: Option<usize>,
+
+    /// Hours a shipment's Idempotency-Key is remembered, 0 ignores the header
+    #[arg(long, env = "IDEMPOTENCY_WINDOW")]
+    pub idempotency_window: Option<u64>,
+
+    /// Retention mode: "delete" or "archive"
+    #[arg(long, env = "MODE", value_parser = Rentention::from_str)]
+    pub mode: Option<Rentention>,
+
+    /// Number of days logs are retained before cleanup
+    #[arg(long, env = "DAYS_RETAINED")]
+    pub days_retained: Option<u32>,
+
+    /// Minutes between scheduled retention runs, 0 disables the scheduler
+    #[arg(long, env = "RETENTION_INTERVAL")]
+    pub retention_interval: Option<u64>,
+
+    /// Directory where expired logs are archived when the mode is "archive"
+    #[arg(long, env = "ARCHIVE_PATH")]
+    pub archive_path: Option<String>,
+
+    /// Where archives are stored: "local" or "s3"
+    #[arg(long, env = "ARCHIVE_TARGET", value_parser = ArchiveTarget::from_str)]
+    pub archive_target: Option<ArchiveTarget>,
+
+    /// S3-compatible endpoint, e.g. http://localhost:9000
+    #[arg(long, env = "S3_ENDPOINT")]
+    pub s3_endpoint: Option<String>,
+
+    /// Bucket archives are uploaded to
+    #[arg(long, env = "S3_BUCKET")]
+    pub s3_bucket: Option<String>,
+
+    /// Key prefix archives are uploaded under
+    #[arg(long, env = "S3_PREFIX")]
+    pub s3_prefix: Option<String>,
+
+    /// Region used to sign S3 requests
+    #[arg(long, env = "S3_REGION")]
+    pub s3_region: Option<String>,
+
+    /// S3 access key ID
+    #[arg(long, env = "S3_ACCESS_KEY", hide_env_values = true)]
+    pub s3_access_key: Option<String>,
+
+    /// S3 secret access key
+    #[arg(long, env = "S3_SECRET_KEY", hide_env_values = true)]
+    pub s3_secret_key: Option<String>,
+
+    /// Use path-style bucket addressing (true/false)
+    #[arg(long, env = "S3_PATH_STYLE", value_parser = parse_bool)]
+    pub s3_path_style: Option<bool>,
+
+    /// Discord webhook URL notifications are sent to
+    #[arg(long, env = "DISCORD_WEBHOOK_URL", hide_env_values = true)]
+    pub discord_webhook_url: Option<String>,
+
+    /// Slack webhook URL notifications are sent to
+    #[arg(long, env = "SLACK_WEBHOOK_URL", hide_env_values = true)]
+    pub slack_webhook_url: Option<String>,
+
+    /// Microsoft Teams webhook URL notifications are sent to
+    #[arg(long, env = "TEAMS_WEBHOOK_URL", hide_env_values = true)]
+    pub teams_webhook_url: Option<String>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum CliCommand {
+    /// Run the HTTP server (default)
+    Serve,
+    /// Restore archived days of an app back into live storage
+    Restore {
+        /// App to restore
+        #[arg(long)]
+        app: String,
+        /// First day to restore (YYYY-MM-DD)
+        #[arg(long)]
+        from: NaiveDate,
+        /// Last day to restore (YYYY-MM-DD), defaults to `from`
+        #[arg(long)]
+        to: Option<NaiveDate>,
+    },
+}
+
+/// Shape of the optional TOML config file. Every key is optional and falls
+/// back to the default when missing.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    storage_path: Option<String>,
+    master_key: Option<String>,
+    port: Option<u16>,
+    max_request_size: Option<u64>,
+    max_entry_size: Option<usize>,
+    idempotency_window: Option<u64>,
+    level_aliases: Option<HashMap<String, HashMap<String, String>>>,
+    mode: Option<String>,
+    days_retained: Option<u32>,
+    retention_interval: Option<u64>,
+    retention: Option<Vec<FileRetentionPolicy>>,
+    archive_path: Option<String>,
+    archive_target: Option<String>,
+    s3: Option<FileS3Config>,
+    discord: Option<FileWebhookConfig>,
+    slack: Option<FileWebhookConfig>,
+    teams: Option<FileWebhookConfig>,
+    syslog: Option<Vec<FileListener>>,
+    gelf: Option<Vec<FileListener>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileRetentionPolicy {
+    app: Option<String>,
+    levels: Option<Vec<String>>,
+    envs: Option<Vec<String>>,
+    days: u32,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileS3Config {
+    endpoint: Option<String>,
+    bucket: Option<String>,
+    prefix: Option<String>,
+    region: Option<String>,
+    access_key: Option<String>,
+    secret_key: Option<String>,
+    path_style: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileListener {
+    protocol: String,
+    bind: String,
+    apps: Option<Vec<String>>,
+    app: Option<String>,
+    env: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileWebhookConfig {
+    webhook_url: Option<String>,
+    levels: Option<Vec<String>>,
+}
+
+impl Config {
+    /// Loads the config from the process arguments and environment, along with
+    /// the subcommand to run.
+    pub fn load() -> Result<(Config, CliCommand)> {
+        let _ = dotenvy::dotenv();
+        let mut cli = Cli::parse();
+        let command = cli.command.take().unwrap_or(CliCommand::Serve);
+        Ok((Config::from_cli(cli)?, command))
+    }
+
+    pub fn from_cli(cli: Cli) -> Result<Config> {
+        let file = match &cli.config {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .with_context(|| format!("failed to read config file {}", path.display()))?;
+                toml::from_str::<FileConfig>(&contents)
+                    .with_context(|| format!("failed to parse config file {}", path.display()))?
+            }
+            None => FileConfig::default(),
+        };
+
+        let mut config = Config::default();
+
+        if let Some(storage_path) = cli.storage_path.or(file.storage_path) {
+            config.storage_path = storage_path;
+        }
+        if let Some(master_key) = cli.master_key.or(file.master_key) {
+            config.master_key = master_key;
+        }
+        if let Some(port) = cli.port.or(file.port) {
+            config.port = port;
+        }
+        if let Some(max_request_size) = cli.max_request_size.or(file.max_request_size) {
+            config.max_request_size = max_request_size;
+        }
+        if let Some(max_entry_size) = cli.max_entry_size.or(file.max_entry_size) {
+            config.max_entry_size = max_entry_size;
+        }
+        if let Some(idempotency_window) = cli.idempotency_window.or(file.idempotency_window) {
+            config.idempotency_window = idempotency_window;
+        }
+        for (app, aliases) in file.level_aliases.unwrap_or_default() {
+            let mut levels = HashMap::new();
+            for (alias, level) in aliases {
+                let level = level
+                    .parse::<ErrorLevel>()
+                    .with_context(|| format!("invalid level alias \"{}\" for {}", alias, app))?;
+                levels.insert(alias.trim().to_uppercase(), level);
+            }
+            config
+                .level_aliases
+                .insert(crate::to_kebab_case(&app), levels);
+        }
+        match (cli.mode, file.mode) {
+            (Some(mode), _) => config.mode = mode,
+            (None, Some(mode)) => config.mode = mode.parse().context("invalid mode")?,
+            (None, None) => {}
+        }
+        if let Some(days_retained) = cli.days_retained.or(file.days_retained) {
+            config.days_retained = days_retained;
+        }
+        if let Some(retention_interval) = cli.retention_interval.or(file.retention_interval) {
+            config.retention_interval = retention_interval;
+        }
+        for (i, policy) in file.retention.unwrap_or_default().into_iter().enumerate() {
+            let levels = policy
+                .levels
+                .unwrap_or_default()
+                .iter()
+                .map(|level| level.parse::<ErrorLevel>())
+                .collect::<Result<Vec<ErrorLevel>>>()
+                .with_context(|| format!("invalid retention policy #{}", i + 1))?;
+            if policy.days == 0 {
+                return Err(anyhow!(
+                    "retention policy #{} must retain logs for at least 1 day",
+                    i + 1
+                ));
+            }
+            config.retention.push(RetentionPolicy {
+                app: policy.app.map(|app| crate::to_kebab_case(&app)),
+                levels,
+                envs: policy.envs.unwrap_or_default(),
+                days: policy.days,
+            });
+        }
+        if let Some(archive_path) = cli.archive_path.or(file.archive_path) {
+            config.archive_path = archive_path;
+        }
+        match (cli.archive_target, file.archive_target) {
+            (Some(target), _) => config.archive_target = target,
+            (None, Some(target)) => {
+                config.archive_target = target.parse().context("invalid archive_target")?
+            }
+            (None, None) => {}
+        }
+
+        let s3 = file.s3.unwrap_or_default();
+        if let Some(endpoint) = cli.s3_endpoint.or(s3.endpoint) {
+            config.s3.endpoint = endpoint;
+        }
+        if let Some(bucket) = cli.s3_bucket.or(s3.bucket) {
+            config.s3.bucket = bucket;
+        }
+        if let Some(prefix) = cli.s3_prefix.or(s3.prefix) {
+            config.s3.prefix = prefix;
+        }
+        if let Some(region) = cli.s3_region.or(s3.region) {
+            config.s3.region = region;
+        }
+        if let Some(access_key) = cli.s3_access_key.or(s3.access_key) {
+            config.s3.access_key = access_key;
+        }
+        if let Some(secret_key) = cli.s3_secret_key.or(s3.secret_key) {
+            config.s3.secret_key = secret_key;
+        }
+        if let Some(path_style) = cli.s3_path_style.or(s3.path_style) {
+            config.s3.path_style = path_style;
+        }
+
+        config.discord = webhook_config("DISCORD", cli.discord_webhook_url, file.discord)?;
+        config.slack = webhook_config("SLACK", cli.slack_webhook_url, file.slack)?;
+        config.teams = webhook_config("TEAMS", cli.teams_webhook_url, file.teams)?;
+        config.syslog = listeners(file.syslog, "syslog")?;
+        config.gelf = listeners(file.gelf, "gelf")?;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Number of days a log is retained, taking the first matching retention
+    /// policy into account.
+    pub fn retention_days(&self, app: &str, level: ErrorLevel, env: &str) -> u32 {
+        self.retention
+            .iter()
+            .find(|policy| policy.matches(app, level, env))
+            .map_or(self.days_retained, |policy| policy.days)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.storage_path.trim().is_empty() {
+            return Err(anyhow!("storage_path must not be empty"));
+        }
+        if self.mode == Rentention::ARCHIVE && self.archive_path.trim().is_empty() {
+            return Err(anyhow!("archive_path must not be empty in archive mode"));
+        }
+        if self.mode == Rentention::ARCHIVE && self.archive_target == ArchiveTarget::S3 {
+            for (name, value) in [
+                ("s3 endpoint", &self.s3.endpoint),
+                ("s3 bucket", &self.s3.bucket),
+                ("s3 region", &self.s3.region),
+                ("s3 access key", &self.s3.access_key),
+                ("s3 secret key", &self.s3.secret_key),
+            ] {
+                if value.trim().is_empty() {
+                    return Err(anyhow!("{} is required when archiving to s3", name));
+                }
+            }
+            if !self.s3.endpoint.starts_with("http") {
+                return Err(anyhow!("s3 endpoint must start with http:// or https://"));
+            }
+        }
+        let listeners = self
+            .syslog
+            .iter()
+            .map(|listener| ("syslog", listener))
+            .chain(self.gelf.iter().map(|listener| ("gelf", listener)));
+        for (kind, listener) in listeners {
+            if listener.apps.is_empty() {
+                return Err(anyhow!(
+                    "{} listener on {} must list the apps it may write to",
+                    kind,
+                    listener.bind
+                ));
+            }
+            if let Some(app) = &listener.app {
+                if !listener.allows(app) {
+                    return Err(anyhow!(
+                        "{} listener on {} writes to {} which is not in its apps",
+                        kind,
+                        listener.bind,
+                        app
+                    ));
+                }
+            }
+        }
+        if self.port == 0 {
+            return Err(anyhow!("port must be between 1 and 65535"));
+        }
+        if self.max_request_size == 0 || self.max_entry_size == 0 {
+            return Err(anyhow!(
+                "max_request_size and max_entry_size must be at least 1 byte"
+            ));
+        }
+        if self.days_retained == 0 {
+            return Err(anyhow!("days_retained must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+/// Resolves the `[[syslog]]` or `[[gelf]]` listeners of the config file. A
+/// listener that sets `app` may write to that app unless it lists `apps`.
+fn listeners(file: Option<Vec<FileListener>>, default_env: &str) -> Result<Vec<Listener>> {
+    let mut listeners = Vec::new();
+    for listener in file.unwrap_or_default() {
+        let app = listener.app.map(|app| crate::to_kebab_case(&app));
+        let apps = match (listener.apps, &app) {
+            (Some(apps), _) => apps.iter().map(|app| crate::to_kebab_case(app)).collect(),
+            (None, Some(app)) => vec![app.clone()],
+            (None, None) => Vec::new(),
+        };
+        listeners.push(Listener {
+            protocol: listener.protocol.parse()?,
+            bind: listener.bind,
+            apps,
+            app,
+            env: listener.env.unwrap_or_else(|| default_env.to_string()),
+        });
+    }
+    Ok(listeners)
+}
+
+/// Resolves a webhook from its CLI/env URL, the `[provider]` table of the config
+/// file and the `<PROVIDER>_SEND_<LEVEL>` environment toggles.
+fn webhook_config(
+    provider: &str,
+    url: Option<String>,
+    file: Option<FileWebhookConfig>,
+) -> Result<WebhookConfig> {
+    let file = file.unwrap_or_default();
+    let mut webhook = WebhookConfig {
+        url: url.or(file.webhook_url).unwrap_or_default(),
+        levels: Vec::new(),
+    };
+
+    for level in file.levels.unwrap_or_default() {
+        let level = level
+            .parse::<ErrorLevel>()
+            .with_context(|| format!("invalid {} level", provider.to_lowercase()))?;
+        if !webhook.levels.contains(&level) {
+            webhook.levels.push(level);
+        }
+    }
+
+    for level in ErrorLevel::ROUTABLE {
+        let key = format!("{}_SEND_{}", provider, level.name().to_uppercase());
+        let value = match env::var(&key) {
+            Ok(value) if !value.trim().is_empty() => value,
+            _ => continue,
+        };
+        if parse_bool(&value).with_context(|| format!("invalid value for {}", key))? {
+            if !webhook.levels.contains(&level) {
+                webhook.levels.push(level);
+            }
+        } else {
+            webhook.levels.retain(|l| *l != level);
+        }
+    }
+
+    if !webhook.url.is_empty() && !webhook.url.starts_with("http") {
+        return Err(anyhow!(
+            "{} webhook URL must start with http:// or https://",
+            provider.to_lowercase()
+        ));
+    }
+
+    Ok(webhook)
+}
+
+fn parse_bool(value: &str) -> Result<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Ok(true),
+        "false" | "0" | "no" | "off" => Ok(false),
+        _ => Err(anyhow!("expected true or false, got \"{}\"", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+    use std::ffi::OsString;
+    use std::sync::{Mutex, MutexGuard};
+
+    /// Parsing reads the environment, so tests that set variables run alone.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Runs a test alone with every variable the config reads unset, and puts
+    /// them back when dropped.
+    struct CleanEnv {
+        saved: Vec<(OsString, OsString)>,
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    fn clean_env() -> CleanEnv {
+        let lock = ENV_LOCK.lock().unwrap_or_else(|error| error.into_inner());
+        let mut names: Vec<OsString> = Cli::command()
+            .get_arguments()
+            .filter_map(|arg| arg.get_env().map(OsString::from))
+            .collect();
+        for provider in ["DISCORD", "SLACK", "TEAMS"] {
+            for level in ErrorLevel::ROUTABLE {
+                let name = format!("{}_SEND_{}", provider, level.name().to_uppercase());
+                names.push(name.into());
+            }
+        }
+        let mut saved = Vec::new();
+        for name in names {
+            if let Some(value) = env::var_os(&name) {
+                env::remove_var(&name);
+                saved.push((name, value));
+            }
+        }
+        CleanEnv { saved, _lock: lock }
+    }
+
+    impl Drop for CleanEnv {
+        fn drop(&mut self) {
+            for (name, value) in &self.saved {
+                env::set_var(name, value);
+            }
+        }
+    }
+
+    fn config_file(contents: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("lumberjack-config-{}.toml", uuid::Uuid::now_v7()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn load(args: &[&str]) -> Result<Config> {
+        let mut argv = vec!["lumberjack"];
+        argv.extend_from_slice(args);
+        Config::from_cli(Cli::try_parse_from(argv)?)
+    }
+
+    #[test]
+    fn cli_and_env_override_the_file_which_overrides_defaults() {
+        let _env = clean_env();
+        let path = config_file(
+            r#"
+            port = 8000
+            days_retained = 30
+            idempotency_window = 6
+            mode = "archive"
+            "#,
+        );
+        let path = path.to_str().unwrap();
+
+        let config = load(&[]).unwrap();
+        assert_eq!(config.port, 7777);
+        assert_eq!(config.days_retained, 14);
+        assert_eq!(config.mode, Rentention::DELETE);
+
+        let config = load(&["--config", path]).unwrap();
+        assert_eq!(config.port, 8000);
+        assert_eq!(config.days_retained, 30);
+        assert_eq!(config.idempotency_window, 6);
+        assert_eq!(config.mode, Rentention::ARCHIVE);
+        assert_eq!(config.storage_path, "./data");
+
+        std::env::set_var("IDEMPOTENCY_WINDOW", "12");
+        let config = load(&["--config", path, "--port", "9000", "--mode", "delete"]);
+        std::env::remove_var("IDEMPOTENCY_WINDOW");
+        let config = config.unwrap();
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.idempotency_window, 12);
+        assert_eq!(config.mode, Rentention::DELETE);
+        assert_eq!(config.days_retained, 30);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let _env = clean_env();
+        let error = load(&["--days-retained", "0"]).unwrap_err();
+        assert_eq!(error.to_string(), "days_retained must be at least 1");
+        assert!(load(&["--mode", "shred"]).is_err());
+
+        let path = config_file(
+            r#"
+            mode = "archive"
+            archive_target = "s3"
+            "#,
+        );
+        let error = load(&["--config", path.to_str().unwrap()]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "s3 endpoint is required when archiving to s3"
+        );
+        fs::remove_file(path).unwrap();
+
+        let path = config_file(
+            r#"
+            [[syslog]]
+            protocol = "udp"
+            bind = "0.0.0.0:5514"
+            "#,
+        );
+        let error = load(&["--config", path.to_str().unwrap()]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "syslog listener on 0.0.0.0:5514 must list the apps it may write to"
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_unreadable_and_malformed_files() {
+        let _env = clean_env();
+        let error = load(&["--config", "/nonexistent/lumberjack.toml"]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "failed to read config file /nonexistent/lumberjack.toml"
+        );
+
+        for contents in ["port = \"eighty\"", "port = ", "colour = \"blue\""] {
+            let path = config_file(contents);
+            let error = load(&["--config", path.to_str().unwrap()]).unwrap_err();
+            assert!(error.to_string().starts_with("failed to parse config file"));
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
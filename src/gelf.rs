@@ -0,0 +1,468 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::net::{TcpListener, UdpSocket};
+
+use crate::config::{Config, Listener, ListenerProtocol};
+use crate::ingest::empty_log;
+use crate::{to_kebab_case, write_log_files, ErrorLevel, Log};
+
+const CHUNK_MAGIC: [u8; 2] = [0x1e, 0x0f];
+const CHUNK_HEADER_SIZE: usize = 12;
+const MAX_CHUNKS: u8 = 128;
+/// Graylog drops chunked messages that aren't complete within five seconds.
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Chunked messages waiting for their remaining chunks. The oldest are dropped
+/// when either limit is reached, so a flood of chunks can't exhaust memory.
+const MAX_PENDING_MESSAGES: usize = 1024;
+const MAX_PENDING_BYTES: usize = 32 * 1024 * 1024;
+
+/// Binds every configured GELF listener and spawns the tasks serving them.
+pub async fn start(config: &Config) -> Result<()> {
+    let storage_path = PathBuf::from(&config.storage_path);
+    for listener in config.gelf.iter() {
+        let storage_path = storage_path.clone();
+        let max_message_size = config.max_entry_size;
+        match listener.protocol {
+            ListenerProtocol::Udp => {
+                let socket = UdpSocket::bind(&listener.bind)
+                    .await
+                    .with_context(|| format!("Failed to bind GELF UDP {}", listener.bind))?;
+                tokio::spawn(serve_udp(
+                    socket,
+                    listener.clone(),
+                    storage_path,
+                    max_message_size,
+                ));
+            }
+            ListenerProtocol::Tcp => {
+                let socket = TcpListener::bind(&listener.bind)
+                    .await
+                    .with_context(|| format!("Failed to bind GELF TCP {}", listener.bind))?;
+                tokio::spawn(serve_tcp(
+                    socket,
+                    listener.clone(),
+                    storage_path,
+                    max_message_size,
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn serve_udp(
+    socket: UdpSocket,
+    listener: Listener,
+    storage_path: PathBuf,
+    max_message_size: usize,
+) {
+    let mut chunks = ChunkAssembler::new(max_message_size);
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let size = match socket.recv_from(&mut buffer).await {
+            Ok((size, _)) => size,
+            Err(error) => {
+                eprintln!("GELF UDP {}: {}", listener.bind, error);
+                continue;
+            }
+        };
+        let datagram = &buffer[..size];
+        if !datagram.starts_with(&CHUNK_MAGIC) {
+            handle(&listener, &storage_path, datagram, max_message_size);
+            continue;
+        }
+        match chunks.add(datagram, Instant::now()) {
+            Ok(Some(payload)) => handle(&listener, &storage_path, &payload, max_message_size),
+            Ok(None) => {}
+            Err(error) => eprintln!("GELF UDP {}: {:#}", listener.bind, error),
+        }
+    }
+}
+
+/// GELF over TCP sends uncompressed messages, each terminated by a null byte.
+async fn serve_tcp(
+    socket: TcpListener,
+    listener: Listener,
+    storage_path: PathBuf,
+    max_message_size: usize,
+) {
+    loop {
+        let stream = match socket.accept().await {
+            Ok((stream, _)) => stream,
+            Err(error) => {
+                eprintln!("GELF TCP {}: {}", listener.bind, error);
+                continue;
+            }
+        };
+        let listener = listener.clone();
+        let storage_path = storage_path.clone();
+        tokio::spawn(async move {
+            let mut reader = BufReader::new(stream);
+            loop {
+                let mut frame = Vec::new();
+                let read = (&mut reader)
+                    .take(max_message_size as u64 + 1)
+                    .read_until(b'\0', &mut frame)
+                    .await;
+                match read {
+                    Ok(0) => break,
+                    Ok(_) if frame.len() > max_message_size => {
+                        eprintln!(
+                            "GELF TCP {}: Message exceeds the maximum of {} bytes",
+                            listener.bind, max_message_size
+                        );
+                        break;
+                    }
+                    Ok(_) => {
+                        let frame = frame.strip_suffix(b"\0").unwrap_or(&frame);
+                        handle(&listener, &storage_path, frame, max_message_size);
+                    }
+                    Err(error) => {
+                        eprintln!("GELF TCP {}: {}", listener.bind, error);
+                        break;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Parses a message and writes it to its app when the listener allows it.
+fn handle(listener: &Listener, storage_path: &Path, payload: &[u8], max_message_size: usize) {
+    if payload.iter().all(|byte| byte.is_ascii_whitespace()) {
+        return;
+    }
+    let parsed = decompress(payload, max_message_size).and_then(|message| parse(&message));
+    let (message_app, mut log) = match parsed {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            eprintln!("GELF {}: dropping message: {:#}", listener.bind, error);
+            return;
+        }
+    };
+    if let (Some(_), Some(message_app)) = (&listener.app, &message_app) {
+        log.custom.insert("app".to_string(), message_app.clone());
+    }
+    let Some(app) = listener
+        .app
+        .clone()
+        .or(message_app.map(|app| to_kebab_case(&app)))
+        .filter(|app| !app.is_empty())
+    else {
+        eprintln!(
+            "GELF {}: dropping message without an _app field",
+            listener.bind
+        );
+        return;
+    };
+    if !listener.allows(&app) {
+        eprintln!(
+            "GELF {}: dropping message for unauthorized app {}",
+            listener.bind, app
+        );
+        return;
+    }
+
+    log.env = listener.env.clone();
+    if let Err(error) = write_log_files(vec![log], storage_path.join(&app)) {
+        eprintln!("GELF {}: failed to write log: {:#}", listener.bind, error);
+    }
+}
+
+/// Reassembles chunked UDP messages, which are split into at most 128
+/// datagrams that share an 8 byte message id.
+pub struct ChunkAssembler {
+    messages: HashMap<[u8; 8], PartialMessage>,
+    max_message_size: usize,
+}
+
+struct PartialMessage {
+    chunks: Vec<Option<Vec<u8>>>,
+    received: usize,
+    size: usize,
+    started: Instant,
+}
+
+impl ChunkAssembler {
+    pub fn new(max_message_size: usize) -> ChunkAssembler {
+        ChunkAssembler {
+            messages: HashMap::new(),
+            max_message_size,
+        }
+    }
+
+    fn pending_bytes(&self) -> usize {
+        self.messages.values().map(|message| message.size).sum()
+    }
+
+    /// Drops the oldest pending message other than `keep`, returning whether
+    /// there was one.
+    fn drop_oldest(&mut self, keep: &[u8; 8]) -> bool {
+        let oldest = self
+            .messages
+            .iter()
+            .filter(|(id, _)| *id != keep)
+            .min_by_key(|(_, message)| message.started)
+            .map(|(id, _)| *id);
+        match oldest {
+            Some(id) => self.messages.remove(&id).is_some(),
+            None => false,
+        }
+    }
+
+    /// Adds a chunk, returning the whole payload once its last chunk arrives.
+    pub fn add(&mut self, datagram: &[u8], now: Instant) -> Result<Option<Vec<u8>>> {
+        self.messages
+            .retain(|_, message| now.duration_since(message.started) < CHUNK_TIMEOUT);
+
+        if datagram.len() < CHUNK_HEADER_SIZE || !datagram.starts_with(&CHUNK_MAGIC) {
+            return Err(anyhow!("Chunk header is incomplete"));
+        }
+        let id: [u8; 8] = datagram[2..10].try_into()?;
+        let sequence = datagram[10];
+        let count = datagram[11];
+        if count == 0 || count > MAX_CHUNKS || sequence >= count {
+            return Err(anyhow!("Chunk {} of {} is out of range", sequence, count));
+        }
+
+        if !self.messages.contains_key(&id) {
+            while self.messages.len() >= MAX_PENDING_MESSAGES && self.drop_oldest(&id) {}
+        }
+        let message = self.messages.entry(id).or_insert_with(|| PartialMessage {
+            chunks: vec![None; count as usize],
+            received: 0,
+            size: 0,
+            started: now,
+        });
+        if message.chunks.len() != count as usize {
+            return Err(anyhow!("Chunks disagree on the number of chunks"));
+        }
+        let chunk = &mut message.chunks[sequence as usize];
+        if chunk.is_none() {
+            let data = &datagram[CHUNK_HEADER_SIZE..];
+            *chunk = Some(data.to_vec());
+            message.received += 1;
+            message.size += data.len();
+        }
+        if message.size > self.max_message_size {
+            self.messages.remove(&id);
+            return Err(anyhow!(
+                "Message exceeds the maximum of {} bytes",
+                self.max_message_size
+            ));
+        }
+        while self.pending_bytes() > MAX_PENDING_BYTES && self.drop_oldest(&id) {}
+        let message = &self.messages[&id];
+        if message.received < message.chunks.len() {
+            return Ok(None);
+        }
+
+        let message = self.messages.remove(&id).unwrap();
+        Ok(Some(
+            message.chunks.into_iter().flatten().flatten().collect(),
+        ))
+    }
+}
+
+/// Inflates gzip and zlib payloads, which are recognised by their magic bytes.
+/// Anything else is taken to be uncompressed JSON.
+pub fn decompress(payload: &[u8], max_message_size: usize) -> Result<Vec<u8>> {
+    let reader: Box<dyn Read + '_> = match payload {
+        [0x1f, 0x8b, ..] => Box::new(GzDecoder::new(payload)),
+        [0x78, second, ..] if (0x78u16 * 256 + *second as u16).is_multiple_of(31) => {
+            Box::new(ZlibDecoder::new(payload))
+        }
+        _ => Box::new(payload),
+    };
+    let mut message = Vec::new();
+    reader
+        .take(max_message_size as u64 + 1)
+        .read_to_end(&mut message)
+        .context("Failed to decompress message")?;
+    if message.len() > max_message_size {
+        return Err(anyhow!(
+            "Message exceeds the maximum of {} bytes",
+            max_message_size
+        ));
+    }
+    Ok(message)
+}
+
+/// Parses a GELF message into a log, along with the app named by its `_app`
+/// field.
+pub fn parse(message: &[u8]) -> Result<(Option<String>, Log)> {
+    let value: Value = serde_json::from_slice(message).context("Message is not JSON")?;
+    let fields = value
+        .as_object()
+        .ok_or_else(|| anyhow!("Message is not a JSON object"))?;
+    let text = |name: &str| match fields.get(name) {
+        None | Some(Value::Null) => None,
+        Some(Value::String(value)) => Some(value.clone()),
+        Some(value) => Some(value.to_string()),
+    };
+
+    let mut log = empty_log();
+    // The full message usually repeats the short one followed by a stack trace.
+    log.message = text("full_message")
+        .filter(|message| !message.trim().is_empty())
+        .or_else(|| text("short_message"))
+        .ok_or_else(|| anyhow!("Message has no short_message"))?;
+    log.level = text("level")
+        .and_then(|level| ErrorLevel::from_alias(&level))
+        .unwrap_or(ErrorLevel::Unknown);
+    let timestamp = fields
+        .get("timestamp")
+        .and_then(|timestamp| match timestamp {
+            Value::String(timestamp) => timestamp.parse::<f64>().ok(),
+            timestamp => timestamp.as_f64(),
+        })
+        .and_then(|seconds| DateTime::from_timestamp_micros((seconds * 1_000_000.0) as i64))
+        .unwrap_or_else(Utc::now);
+    log.timestamp = timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true);
+
+    let mut app = None;
+    for (name, value) in fields {
+        let value = match value {
+            Value::String(value) => value.clone(),
+            value => value.to_string(),
+        };
+        match name.as_str() {
+            "version" | "short_message" | "full_message" | "level" | "timestamp" | "_id" => {}
+            // `file` and `line` were deprecated in GELF 1.1 for `_file` and `_line`.
+            "_file" | "file" => log.file = value,
+            "_line" | "line" => log.line = value.parse().ok(),
+            "_function" => log.function = value,
+            "_category" => log.category = value,
+            "_app" => app = Some(value),
+            name => {
+                let name = name.strip_prefix('_').unwrap_or(name);
+                log.custom.insert(name.to_string(), value);
+            }
+        }
+    }
+    Ok((app, log))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use std::io::Write;
+
+    const MESSAGE: &str = r#"{
+        "version": "1.1",
+        "host": "app-01",
+        "short_message": "NullPointerException",
+        "full_message": "NullPointerException\n\tat com.example.Billing.charge(Billing.java:42)",
+        "timestamp": 1712393304.25,
+        "level": 3,
+        "_app": "Billing Service",
+        "_file": "Billing.java",
+        "_line": 42,
+        "_thread": "main"
+    }"#;
+
+    #[test]
+    fn parses_messages() {
+        let (app, log) = parse(MESSAGE.as_bytes()).unwrap();
+        assert_eq!(app.as_deref(), Some("Billing Service"));
+        assert_eq!(log.level, ErrorLevel::Error);
+        assert_eq!(log.timestamp, "2024-04-06T08:48:24.250Z");
+        assert!(log
+            .message
+            .starts_with("NullPointerException\n\tat com.example"));
+        assert_eq!(log.file, "Billing.java");
+        assert_eq!(log.line, Some(42));
+        assert_eq!(log.custom["host"], "app-01");
+        assert_eq!(log.custom["thread"], "main");
+        assert!(!log.custom.contains_key("version"));
+
+        let (_, log) = parse(br#"{"short_message": "hi", "host": "a"}"#).unwrap();
+        assert_eq!(log.message, "hi");
+        assert_eq!(log.level, ErrorLevel::Unknown);
+        assert!(parse(br#"{"host": "a"}"#).is_err());
+        assert!(parse(b"[]").is_err());
+    }
+
+    #[test]
+    fn decompresses_gzip_and_zlib() {
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(MESSAGE.as_bytes()).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(MESSAGE.as_bytes()).unwrap();
+        let zlib = zlib.finish().unwrap();
+
+        for payload in [gzip, zlib] {
+            assert_eq!(decompress(&payload, 4096).unwrap(), MESSAGE.as_bytes());
+            assert!(decompress(&payload, 16).is_err());
+        }
+        assert_eq!(
+            decompress(MESSAGE.as_bytes(), 4096).unwrap(),
+            MESSAGE.as_bytes()
+        );
+        assert!(decompress(MESSAGE.as_bytes(), 16).is_err());
+    }
+
+    fn chunk(id: u8, sequence: u8, count: u8, data: &[u8]) -> Vec<u8> {
+        let mut datagram = CHUNK_MAGIC.to_vec();
+        datagram.extend([id; 8]);
+        datagram.extend([sequence, count]);
+        datagram.extend(data);
+        datagram
+    }
+
+    #[test]
+    fn reassembles_chunks_in_any_order() {
+        let mut chunks = ChunkAssembler::new(4096);
+        let now = Instant::now();
+        assert_eq!(chunks.add(&chunk(1, 2, 3, b"ef"), now).unwrap(), None);
+        assert_eq!(chunks.add(&chunk(2, 0, 2, b"xx"), now).unwrap(), None);
+        assert_eq!(chunks.add(&chunk(1, 0, 3, b"ab"), now).unwrap(), None);
+        assert_eq!(
+            chunks.add(&chunk(1, 1, 3, b"cd"), now).unwrap(),
+            Some(b"abcdef".to_vec())
+        );
+
+        // Message 2 expires before its last chunk arrives.
+        let later = now + CHUNK_TIMEOUT;
+        assert_eq!(chunks.add(&chunk(2, 1, 2, b"yy"), later).unwrap(), None);
+        assert!(chunks.add(&chunk(3, 3, 2, b""), later).is_err());
+        assert!(chunks.add(&chunk(3, 0, 129, b""), later).is_err());
+    }
+
+    #[test]
+    fn limits_pending_messages() {
+        let now = Instant::now();
+        let mut chunks = ChunkAssembler::new(4);
+        assert_eq!(chunks.add(&chunk(1, 0, 2, b"abc"), now).unwrap(), None);
+        assert!(chunks.add(&chunk(1, 1, 2, b"de"), now).is_err());
+        assert!(chunks.messages.is_empty());
+
+        let mut chunks = ChunkAssembler::new(MAX_PENDING_BYTES);
+        for id in 0..=MAX_PENDING_MESSAGES as u64 {
+            let mut datagram = chunk(0, 0, 2, b"");
+            datagram[2..10].copy_from_slice(&id.to_be_bytes());
+            let started = now + Duration::from_millis(id);
+            assert_eq!(chunks.add(&datagram, started).unwrap(), None);
+        }
+        assert_eq!(chunks.messages.len(), MAX_PENDING_MESSAGES);
+        assert!(!chunks.messages.contains_key(&0u64.to_be_bytes()));
+
+        // A large chunk pushes out older messages instead of growing past the limit.
+        let mut chunks = ChunkAssembler::new(MAX_PENDING_BYTES);
+        let half = vec![0u8; MAX_PENDING_BYTES / 2 + 1];
+        assert_eq!(chunks.add(&chunk(1, 0, 2, &half), now).unwrap(), None);
+        assert_eq!(chunks.add(&chunk(2, 0, 2, &half), now).unwrap(), None);
+        assert_eq!(chunks.messages.len(), 1);
+        assert!(chunks.messages.contains_key(&[2; 8]));
+    }
+}
@@ -0,0 +1,151 @@
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::{self, read_dir};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Directory of an app's storage where completed shipments are recorded.
+const DIRECTORY: &str = "idempotency";
+
+lazy_static! {
+    /// Records of shipments that are still being written.
+    static ref IN_FLIGHT: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+}
+
+/// The outcome of a shipment sent with an `Idempotency-Key`, replayed when the
+/// same key is sent again.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Record {
+    pub created: DateTime<Utc>,
+    /// SHA-256 of the decompressed body, so a key can't be reused for other logs.
+    pub body_hash: String,
+    pub status: u16,
+    pub response: String,
+}
+
+/// Marks a key as in flight until dropped.
+pub struct InFlight {
+    path: PathBuf,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.lock().unwrap().remove(&self.path);
+    }
+}
+
+/// Keys are hashed so any header value makes a valid file name.
+pub fn record_path(app_path: &Path, key: &str) -> PathBuf {
+    app_path.join(DIRECTORY).join(format!(
+        "{}.json",
+        hex::encode(Sha256::digest(key.as_bytes()))
+    ))
+}
+
+/// Claims a key, or returns `None` while another request holds it.
+pub fn begin(path: &Path) -> Option<InFlight> {
+    let mut in_flight = IN_FLIGHT.lock().unwrap();
+    if !in_flight.insert(path.to_path_buf()) {
+        return None;
+    }
+    Some(InFlight {
+        path: path.to_path_buf(),
+    })
+}
+
+/// Returns the record of a key used within the window.
+pub fn lookup(path: &Path, window_hours: u64, now: DateTime<Utc>) -> Option<Record> {
+    let record: Record = serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+    if is_expired(&record, window_hours, now) {
+        return None;
+    }
+    Some(record)
+}
+
+pub fn save(path: &Path, record: &Record) -> Result<()> {
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    // Written to a temporary file first so a crash never leaves half a record.
+    let temp_path = path.with_extension("json.tmp");
+    fs::write(&temp_path, serde_json::to_vec(record)?)?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Removes an app's records that are older than the window, returning how
+/// many were removed.
+pub fn expire(app_path: &Path, window_hours: u64, now: DateTime<Utc>) -> Result<usize> {
+    let directory = app_path.join(DIRECTORY);
+    if !directory.exists() {
+        return Ok(0);
+    }
+    let mut removed = 0;
+    for entry in read_dir(directory)? {
+        let path = entry?.path();
+        let expired = match fs::read(&path)
+            .ok()
+            .and_then(|record| serde_json::from_slice::<Record>(&record).ok())
+        {
+            Some(record) => is_expired(&record, window_hours, now),
+            None => true,
+        };
+        if expired && !IN_FLIGHT.lock().unwrap().contains(&path) {
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+fn is_expired(record: &Record, window_hours: u64, now: DateTime<Utc>) -> bool {
+    now - record.created >= Duration::hours(window_hours as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(created: DateTime<Utc>) -> Record {
+        Record {
+            created,
+            body_hash: "abc".to_string(),
+            status: 200,
+            response: r#"{"accepted":1,"rejected":[]}"#.to_string(),
+        }
+    }
+
+    #[test]
+    fn replays_records_within_the_window() {
+        let app_path =
+            std::env::temp_dir().join(format!("lumberjack-idempotency-{}", uuid::Uuid::now_v7()));
+        let now = Utc::now();
+        let path = record_path(&app_path, "shipment 1");
+        assert!(lookup(&path, 24, now).is_none());
+
+        save(&path, &record(now)).unwrap();
+        assert_eq!(lookup(&path, 24, now).unwrap().body_hash, "abc");
+        assert!(lookup(&path, 24, now + Duration::hours(24)).is_none());
+
+        let old_path = record_path(&app_path, "shipment 0");
+        save(&old_path, &record(now - Duration::hours(30))).unwrap();
+        assert_eq!(expire(&app_path, 24, now).unwrap(), 1);
+        assert!(!old_path.exists());
+        assert!(path.exists());
+
+        fs::remove_dir_all(&app_path).unwrap();
+    }
+
+    #[test]
+    fn claims_keys_once() {
+        let path = record_path(Path::new("/tmp/app"), "in flight");
+        let guard = begin(&path).unwrap();
+        assert!(begin(&path).is_none());
+        drop(guard);
+        assert!(begin(&path).is_some());
+    }
+}
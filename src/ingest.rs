@@ -0,0 +1,984 @@
+use chrono::DateTime;
+use core::iter::Peekable;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::slice::Iter;
+use uuid::Uuid;
+
+use crate::{ErrorLevel, Log};
+
+/// Marks the end of a log entry in a log file.
+pub const EOL: &str = "---[EOL]---";
+
+/// Longest first line echoed back in a rejection.
+const MAX_FIRST_LINE_CHARS: usize = 200;
+
+/// Response of `POST /logs` describing what happened to every entry of the body.
+#[derive(Debug, Default, Serialize)]
+pub struct IngestReport {
+    pub accepted: usize,
+    pub rejected: Vec<RejectedEntry>,
+    /// Set when the request was cut short, e.g. by the maximum request size.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RejectedEntry {
+    /// Position of the entry in the body, starting at 0.
+    pub index: usize,
+    pub first_line: String,
+    pub reason: RejectReason,
+    pub message: String,
+}
+
+impl RejectedEntry {
+    pub fn new(index: usize, first_line: &str, reason: RejectReason) -> RejectedEntry {
+        let mut truncated: String = first_line.chars().take(MAX_FIRST_LINE_CHARS).collect();
+        if truncated.len() < first_line.len() {
+            truncated.push('…');
+        }
+        RejectedEntry {
+            index,
+            first_line: truncated,
+            message: reason.to_string(),
+            reason,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectReason {
+    BadHeader,
+    BadTimestamp,
+    MissingEol,
+    EntryTooLarge,
+    InvalidUtf8,
+    InvalidJson,
+}
+
+impl Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            RejectReason::BadHeader => "First line must be `[LEVEL] - TIMESTAMP`",
+            RejectReason::BadTimestamp => "Timestamp is not a valid RFC 3339 / ISO 8601 date",
+            RejectReason::MissingEol => "Entry is not terminated by ---[EOL]---",
+            RejectReason::EntryTooLarge => "Entry exceeds the maximum entry size",
+            RejectReason::InvalidUtf8 => "Entry is not valid UTF-8",
+            RejectReason::InvalidJson => "Line is not a JSON object",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+/// Response of `POST /logs/validate`, which parses a body without writing it.
+#[derive(Debug, Default, Serialize)]
+pub struct ValidationReport {
+    pub logs: Vec<ValidatedLog>,
+    pub rejected: Vec<RejectedEntry>,
+    pub warnings: Vec<Warning>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ValidatedLog {
+    pub index: usize,
+    pub log: Log,
+}
+
+/// Something about an entry that is probably a mistake, although the entry
+/// may still have been accepted.
+#[derive(Debug, Serialize)]
+pub struct Warning {
+    pub index: usize,
+    pub kind: WarningKind,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningKind {
+    UnknownLevel,
+    MissingTimestamp,
+    MissingMessage,
+    ReservedCustomKey,
+}
+
+/// Fields of a log that a custom key of the same name would be confused with.
+const RESERVED_KEYS: [&str; 5] = ["uid", "level", "timestamp", "env", "custom"];
+
+enum LogSection {
+    Message,
+    File,
+    Function,
+    Line,
+    Custom,
+    Branch,
+    Category,
+}
+
+/// Formats accepted by `POST /logs`, picked by the request's `Content-Type`.
+pub enum BodyParser {
+    /// The `[LEVEL] - TIMESTAMP ... ---[EOL]---` log file format.
+    Text(EntryParser),
+    /// One JSON object per line (`application/x-ndjson`).
+    JsonLines(JsonLinesParser),
+}
+
+impl BodyParser {
+    pub fn new(content_type: &str, max_entry_size: usize) -> BodyParser {
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        if mime.eq_ignore_ascii_case("application/x-ndjson") {
+            BodyParser::JsonLines(JsonLinesParser::new(max_entry_size))
+        } else {
+            BodyParser::Text(EntryParser::new(max_entry_size))
+        }
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<Result<Entry, RejectedEntry>> {
+        match self {
+            BodyParser::Text(parser) => parser.feed(chunk),
+            BodyParser::JsonLines(parser) => parser.feed(chunk),
+        }
+    }
+
+    pub fn finish(&mut self) -> Vec<Result<Entry, RejectedEntry>> {
+        match self {
+            BodyParser::Text(parser) => parser.finish(),
+            BodyParser::JsonLines(parser) => parser.finish(),
+        }
+    }
+
+    /// Builds the log of a complete entry.
+    pub fn create_log(
+        &self,
+        entry: &Entry,
+        level_aliases: &HashMap<String, ErrorLevel>,
+    ) -> Result<Log, RejectReason> {
+        match self {
+            BodyParser::Text(_) => create_log(&entry.lines, level_aliases),
+            BodyParser::JsonLines(_) => create_log_from_json(&entry.lines[0], level_aliases),
+        }
+    }
+
+    /// Warnings about an entry, given the log it was parsed into unless it was
+    /// rejected.
+    pub fn warnings(&self, entry: &Entry, log: Option<&Log>) -> Vec<Warning> {
+        let (level, timestamp, has_message) = match self {
+            BodyParser::Text(_) => {
+                let header = entry.lines[0].trim_start_matches('\u{feff}').trim();
+                let (level, rest) = header
+                    .strip_prefix('[')
+                    .and_then(|header| header.split_once(']'))
+                    .unwrap_or(("", header));
+                let timestamp = rest.trim().trim_start_matches('-').trim();
+                let has_message = entry.lines[1..].iter().any(|line| {
+                    let key = line.split(':').next().unwrap_or("");
+                    key.trim().eq_ignore_ascii_case("message")
+                });
+                (level.to_string(), timestamp.to_string(), has_message)
+            }
+            BodyParser::JsonLines(_) => {
+                let fields = match serde_json::from_str::<Value>(&entry.lines[0]) {
+                    Ok(Value::Object(fields)) => fields,
+                    _ => return Vec::new(),
+                };
+                let field = |name: &str| {
+                    fields
+                        .iter()
+                        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                        .map(|(_, value)| match value {
+                            Value::String(value) => value.clone(),
+                            Value::Null => "".to_string(),
+                            value => value.to_string(),
+                        })
+                };
+                (
+                    field("level").unwrap_or_default(),
+                    field("timestamp").unwrap_or_default(),
+                    field("message").is_some(),
+                )
+            }
+        };
+
+        let mut warnings = Vec::new();
+        let mut warn = |kind, message: String| {
+            warnings.push(Warning {
+                index: entry.index,
+                kind,
+                message,
+            })
+        };
+        if timestamp.trim().is_empty() {
+            warn(
+                WarningKind::MissingTimestamp,
+                "Entry has no timestamp".to_string(),
+            );
+        }
+        let Some(log) = log else {
+            return warnings;
+        };
+        if log.level == ErrorLevel::Unknown {
+            warn(
+                WarningKind::UnknownLevel,
+                format!(
+                    "\"{}\" is not a known level or alias, the entry is stored as Unknown",
+                    level.trim()
+                ),
+            );
+        }
+        if !has_message {
+            warn(
+                WarningKind::MissingMessage,
+                "Entry has no message".to_string(),
+            );
+        } else if log.message.trim().is_empty() {
+            warn(WarningKind::MissingMessage, "Message is empty".to_string());
+        }
+        let mut keys: Vec<&String> = log.custom.keys().collect();
+        keys.sort();
+        for key in keys {
+            if RESERVED_KEYS.contains(&key.to_lowercase().as_str()) {
+                warn(
+                    WarningKind::ReservedCustomKey,
+                    format!(
+                        "Custom key \"{}\" has the same name as the log's {} field",
+                        key,
+                        key.to_lowercase()
+                    ),
+                );
+            }
+        }
+        warnings
+    }
+}
+
+/// Lines of a complete entry, including its header line.
+#[derive(Debug)]
+pub struct Entry {
+    pub index: usize,
+    pub lines: Vec<String>,
+}
+
+/// Incrementally splits a log file into entries as its bytes arrive, so a body
+/// never has to be held in memory as a whole. Only the entry currently being
+/// built is buffered, bounded by `max_entry_size`. Entries that can't be read
+/// are rejected on their own and parsing continues with the next entry.
+pub struct EntryParser {
+    max_entry_size: usize,
+    /// Bytes of a line whose newline hasn't arrived yet.
+    partial_line: Vec<u8>,
+    index: usize,
+    started: bool,
+    first_line: String,
+    lines: Vec<String>,
+    entry_size: usize,
+    rejection: Option<RejectReason>,
+}
+
+impl EntryParser {
+    pub fn new(max_entry_size: usize) -> EntryParser {
+        EntryParser {
+            max_entry_size,
+            partial_line: Vec::new(),
+            index: 0,
+            started: false,
+            first_line: String::new(),
+            lines: Vec::new(),
+            entry_size: 0,
+            rejection: None,
+        }
+    }
+
+    /// Feeds the next chunk of the body and returns every entry it completed.
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<Result<Entry, RejectedEntry>> {
+        let mut entries = Vec::new();
+        let mut rest = chunk;
+        while let Some(newline) = rest.iter().position(|byte| *byte == b'\n') {
+            self.push_bytes(&rest[..newline]);
+            self.grow(1);
+            rest = &rest[newline + 1..];
+            if let Some(entry) = self.end_line() {
+                entries.push(entry);
+            }
+        }
+        self.push_bytes(rest);
+        entries
+    }
+
+    /// Ends the body. Returns the final entry when the body doesn't end with a
+    /// newline after its `---[EOL]---` marker, or rejects an entry that was
+    /// never terminated.
+    pub fn finish(&mut self) -> Vec<Result<Entry, RejectedEntry>> {
+        let mut entries = Vec::new();
+        if !self.partial_line.is_empty() {
+            if let Some(entry) = self.end_line() {
+                entries.push(entry);
+            }
+        }
+        if self.started {
+            self.rejection.get_or_insert(RejectReason::MissingEol);
+            entries.push(self.end_entry());
+        }
+        entries
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        self.grow(bytes.len());
+        if self.rejection.is_some() {
+            // Rejected entries are only scanned for their EOL marker, anything
+            // longer than the marker can't be one.
+            let room = (EOL.len() + 2).saturating_sub(self.partial_line.len());
+            self.partial_line
+                .extend_from_slice(&bytes[..room.min(bytes.len())]);
+        } else {
+            self.partial_line.extend_from_slice(bytes);
+        }
+    }
+
+    /// Counts bytes towards the current entry, rejecting it once it grows past
+    /// the maximum entry size.
+    fn grow(&mut self, bytes: usize) {
+        self.entry_size += bytes;
+        if self.entry_size > self.max_entry_size && self.rejection.is_none() {
+            if !self.started {
+                self.started = true;
+                self.first_line = String::from_utf8_lossy(&self.partial_line).into_owned();
+            }
+            self.reject(RejectReason::EntryTooLarge);
+        }
+    }
+
+    fn end_line(&mut self) -> Option<Result<Entry, RejectedEntry>> {
+        let bytes = std::mem::take(&mut self.partial_line);
+        let mut line = match String::from_utf8(bytes) {
+            Ok(line) => line,
+            Err(error) => {
+                let line = String::from_utf8_lossy(error.as_bytes()).into_owned();
+                if !self.started {
+                    self.started = true;
+                    self.first_line = line.clone();
+                }
+                self.reject(RejectReason::InvalidUtf8);
+                line
+            }
+        };
+        if line.ends_with('\r') {
+            line.pop();
+        }
+
+        if !self.started {
+            // Blank lines between entries are separators, not headers.
+            if line.trim().is_empty() {
+                self.entry_size = 0;
+                return None;
+            }
+            self.started = true;
+            self.first_line = line.clone();
+            self.lines.push(line);
+            return None;
+        }
+        if line == EOL {
+            return Some(self.end_entry());
+        }
+        if self.rejection.is_none() {
+            self.lines.push(line);
+        }
+        None
+    }
+
+    fn reject(&mut self, reason: RejectReason) {
+        if self.rejection.is_none() {
+            self.rejection = Some(reason);
+        }
+        self.lines.clear();
+    }
+
+    fn end_entry(&mut self) -> Result<Entry, RejectedEntry> {
+        let index = self.index;
+        let first_line = std::mem::take(&mut self.first_line);
+        let lines = std::mem::take(&mut self.lines);
+        self.index += 1;
+        self.started = false;
+        self.entry_size = 0;
+
+        match self.rejection.take() {
+            Some(reason) => Err(RejectedEntry::new(index, &first_line, reason)),
+            None => Ok(Entry { index, lines }),
+        }
+    }
+}
+
+/// Builds a log from the lines of an entry, rejecting entries whose header or
+/// timestamp can't be read. `level_aliases` maps the app's own level names,
+/// uppercased, onto levels.
+pub fn create_log(
+    lines: &[String],
+    level_aliases: &HashMap<String, ErrorLevel>,
+) -> Result<Log, RejectReason> {
+    let mut lines = lines.iter().peekable();
+    let mut new_log = empty_log();
+
+    let first_line = lines.next().ok_or(RejectReason::BadHeader)?;
+    (new_log.level, new_log.timestamp) = parse_header(first_line, level_aliases)?;
+
+    loop {
+        let binding = String::from("---[EOL]---");
+        let line = lines.next().unwrap_or(&binding);
+
+        // Values can contain colons of their own (URLs, Windows paths,
+        // timestamps), only the first one separates the key.
+        let (key, value) = line.split_once(':').unwrap_or((line, ""));
+        let key = key.trim();
+        let value = value.trim().to_string();
+
+        let result;
+        let section;
+        match key.to_uppercase().as_str() {
+            "MESSAGE" => {
+                section = LogSection::Message;
+                result = parse_log_message(&mut lines);
+            }
+            "FILE" => {
+                section = LogSection::File;
+                result = value;
+            }
+            "FUNCTION" => {
+                section = LogSection::Function;
+                result = value;
+            }
+            "LINE" => {
+                section = LogSection::Line;
+                result = value;
+            }
+            "CATEGORY" => {
+                section = LogSection::Category;
+                result = value;
+            }
+            "BRANCH" => {
+                section = LogSection::Branch;
+                result = value;
+            }
+            "---[EOL]---" => break,
+            _ => {
+                section = LogSection::Custom;
+                result = value;
+            }
+        }
+        match section {
+            LogSection::Message => new_log.message = result,
+            LogSection::File => new_log.file = result,
+            LogSection::Function => new_log.function = result,
+            LogSection::Line => new_log.line = Some(result.parse().unwrap_or(0)),
+            LogSection::Category => new_log.category = result,
+            LogSection::Branch => new_log.branch = result,
+            LogSection::Custom => {
+                new_log.custom.insert(key.to_string(), result);
+            }
+        }
+    }
+
+    Ok(new_log)
+}
+
+/// Splits a JSON Lines body into one entry per non-blank line, bounded by
+/// `max_entry_size` like the text format.
+pub struct JsonLinesParser {
+    max_entry_size: usize,
+    partial_line: Vec<u8>,
+    index: usize,
+    oversized: bool,
+}
+
+impl JsonLinesParser {
+    pub fn new(max_entry_size: usize) -> JsonLinesParser {
+        JsonLinesParser {
+            max_entry_size,
+            partial_line: Vec::new(),
+            index: 0,
+            oversized: false,
+        }
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<Result<Entry, RejectedEntry>> {
+        let mut entries = Vec::new();
+        let mut rest = chunk;
+        while let Some(newline) = rest.iter().position(|byte| *byte == b'\n') {
+            self.push_bytes(&rest[..newline]);
+            rest = &rest[newline + 1..];
+            if let Some(entry) = self.end_line() {
+                entries.push(entry);
+            }
+        }
+        self.push_bytes(rest);
+        entries
+    }
+
+    pub fn finish(&mut self) -> Vec<Result<Entry, RejectedEntry>> {
+        self.end_line().into_iter().collect()
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        if self.partial_line.len() + bytes.len() > self.max_entry_size {
+            // Only the start of an oversized line is kept for the report.
+            self.oversized = true;
+            let room = (MAX_FIRST_LINE_CHARS * 4).saturating_sub(self.partial_line.len());
+            self.partial_line
+                .extend_from_slice(&bytes[..room.min(bytes.len())]);
+        } else {
+            self.partial_line.extend_from_slice(bytes);
+        }
+    }
+
+    fn end_line(&mut self) -> Option<Result<Entry, RejectedEntry>> {
+        let bytes = std::mem::take(&mut self.partial_line);
+        let oversized = std::mem::take(&mut self.oversized);
+        if !oversized && bytes.iter().all(|byte| byte.is_ascii_whitespace()) {
+            return None;
+        }
+
+        let index = self.index;
+        self.index += 1;
+        if oversized {
+            let line = String::from_utf8_lossy(&bytes);
+            return Some(Err(RejectedEntry::new(
+                index,
+                &line,
+                RejectReason::EntryTooLarge,
+            )));
+        }
+        match String::from_utf8(bytes) {
+            Ok(line) => Some(Ok(Entry {
+                index,
+                lines: vec![line.trim().to_string()],
+            })),
+            Err(error) => Some(Err(RejectedEntry::new(
+                index,
+                &String::from_utf8_lossy(error.as_bytes()),
+                RejectReason::InvalidUtf8,
+            ))),
+        }
+    }
+}
+
+pub fn empty_log() -> Log {
+    Log {
+        uid: Uuid::now_v7().to_string(),
+        level: ErrorLevel::Unknown,
+        file: "".to_string(),
+        function: "".to_string(),
+        line: None,
+        timestamp: "".to_string(),
+        message: "".to_string(),
+        custom: HashMap::new(),
+        branch: "".to_string(),
+        env: "".to_string(),
+        category: "".to_string(),
+    }
+}
+
+/// Builds a log from a JSON Lines entry. The known keys map onto the log's
+/// fields and every other key is stored as a custom field, with non-string
+/// values kept as JSON.
+pub fn create_log_from_json(
+    line: &str,
+    level_aliases: &HashMap<String, ErrorLevel>,
+) -> Result<Log, RejectReason> {
+    let Ok(Value::Object(fields)) = serde_json::from_str::<Value>(line) else {
+        return Err(RejectReason::InvalidJson);
+    };
+
+    let mut new_log = empty_log();
+    for (key, value) in fields {
+        let value = match value {
+            Value::String(value) => value,
+            Value::Null => "".to_string(),
+            value => value.to_string(),
+        };
+        match key.to_lowercase().as_str() {
+            "level" => new_log.level = parse_level(&value, level_aliases),
+            "timestamp" => new_log.timestamp = value.trim().to_string(),
+            "message" => new_log.message = value,
+            "file" => new_log.file = value,
+            "function" => new_log.function = value,
+            "line" => new_log.line = value.trim().parse().ok(),
+            "branch" => new_log.branch = value,
+            "category" => new_log.category = value,
+            _ => {
+                new_log.custom.insert(key, value);
+            }
+        }
+    }
+
+    if DateTime::parse_from_rfc3339(&new_log.timestamp).is_err() {
+        return Err(RejectReason::BadTimestamp);
+    }
+    Ok(new_log)
+}
+
+/// Parses the `[LEVEL] - TIMESTAMP` first line of an entry. Whitespace around
+/// the level, the dash and the timestamp is ignored. Levels that are neither an
+/// app alias nor a known name end up as `ErrorLevel::Unknown`.
+fn parse_header(
+    line: &str,
+    level_aliases: &HashMap<String, ErrorLevel>,
+) -> Result<(ErrorLevel, String), RejectReason> {
+    let line = line.trim_start_matches('\u{feff}').trim();
+    let (level, rest) = line
+        .strip_prefix('[')
+        .and_then(|line| line.split_once(']'))
+        .ok_or(RejectReason::BadHeader)?;
+    let timestamp = rest
+        .trim_start()
+        .strip_prefix('-')
+        .ok_or(RejectReason::BadHeader)?
+        .trim();
+    if DateTime::parse_from_rfc3339(timestamp).is_err() {
+        return Err(RejectReason::BadTimestamp);
+    }
+
+    Ok((parse_level(level, level_aliases), timestamp.to_string()))
+}
+
+/// Resolves a level name through the app's aliases, then the built-in names.
+/// Anything else is `ErrorLevel::Unknown`.
+fn parse_level(name: &str, level_aliases: &HashMap<String, ErrorLevel>) -> ErrorLevel {
+    level_aliases
+        .get(&name.trim().to_uppercase())
+        .copied()
+        .or_else(|| ErrorLevel::from_alias(name))
+        .unwrap_or(ErrorLevel::Unknown)
+}
+
+fn parse_log_message(lines: &mut Peekable<Iter<'_, String>>) -> String {
+    let mut result = String::new();
+
+    loop {
+        match lines
+            .peek()
+            .unwrap_or(&&String::from("---[EOL]---"))
+            .trim()
+            .to_uppercase()
+            .as_str()
+        {
+            "---[EOL]---" => break,
+            _ => {
+                let line = lines.next().unwrap();
+                result = result + "\n" + line.trim();
+            }
+        };
+    }
+
+    result.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GOOD_FIXTURES: [&str; 3] = [
+        include_str!("../test/good-1.log"),
+        include_str!("../test/good-2.log"),
+        include_str!("../test/good-3.log"),
+    ];
+
+    /// Parses a body fed in chunks of `chunk_size` bytes and builds a log for
+    /// every complete entry, like `POST /logs` does.
+    fn parse(body: &str, chunk_size: usize) -> Vec<Result<Log, (usize, RejectReason)>> {
+        let mut parser = EntryParser::new(1024 * 1024);
+        let mut entries = Vec::new();
+        for chunk in body.as_bytes().chunks(chunk_size) {
+            entries.extend(parser.feed(chunk));
+        }
+        entries.extend(parser.finish());
+
+        entries
+            .into_iter()
+            .map(|entry| match entry {
+                Ok(entry) => create_log(&entry.lines, &HashMap::new())
+                    .map_err(|reason| (entry.index, reason)),
+                Err(rejected) => Err((rejected.index, rejected.reason)),
+            })
+            .collect()
+    }
+
+    fn rejections(body: &str) -> Vec<(usize, RejectReason)> {
+        parse(body, body.len())
+            .into_iter()
+            .filter_map(|log| log.err())
+            .collect()
+    }
+
+    #[test]
+    fn accepts_good_fixtures() {
+        for fixture in GOOD_FIXTURES {
+            let logs = parse(fixture, fixture.len());
+            assert_eq!(logs.len(), fixture.matches(EOL).count());
+            assert!(logs.iter().all(|log| log.is_ok()));
+        }
+    }
+
+    #[test]
+    fn parses_fixture_fields() {
+        let logs = parse(GOOD_FIXTURES[0], GOOD_FIXTURES[0].len());
+        let info = logs[0].as_ref().unwrap();
+        assert_eq!(info.level, ErrorLevel::Info);
+        assert_eq!(info.timestamp, "2024-04-06T08:48:24Z");
+        assert_eq!(info.category, "Example");
+        assert_eq!(
+            info.message,
+            "This is the an example message.\nIt can span many lines."
+        );
+
+        let error = logs[1].as_ref().unwrap();
+        assert_eq!(error.level, ErrorLevel::Error);
+        assert_eq!(error.function, "query()");
+        assert_eq!(error.line, Some(69));
+    }
+
+    #[test]
+    fn chunk_boundaries_do_not_change_the_result() {
+        for fixture in GOOD_FIXTURES {
+            let whole: Vec<String> = parse(fixture, fixture.len())
+                .into_iter()
+                .map(|log| log.unwrap().message)
+                .collect();
+            for chunk_size in [1, 7, 64] {
+                let chunked: Vec<String> = parse(fixture, chunk_size)
+                    .into_iter()
+                    .map(|log| log.unwrap().message)
+                    .collect();
+                assert_eq!(chunked, whole);
+            }
+        }
+    }
+
+    #[test]
+    fn skips_blank_lines_between_entries() {
+        let body = GOOD_FIXTURES[1].replace("---[EOL]---\n", "---[EOL]---\n\n  \r\n");
+        let logs = parse(&body, body.len());
+        assert_eq!(logs.len(), GOOD_FIXTURES[1].matches(EOL).count());
+        assert!(logs.iter().all(|log| log.is_ok()));
+    }
+
+    #[test]
+    fn accepts_varied_header_whitespace() {
+        for header in [
+            "[Error] - 2024-04-06T08:48:24Z",
+            "[Error]-2024-04-06T08:48:24Z",
+            "  [ Error ]   -\t2024-04-06T08:48:24Z  ",
+            "\u{feff}[error] -  2024-04-06T08:48:24+02:00",
+        ] {
+            let (level, timestamp) = parse_header(header, &HashMap::new()).unwrap();
+            assert_eq!(level, ErrorLevel::Error, "{:?}", header);
+            assert!(timestamp.starts_with("2024-04-06T08:48:24"));
+        }
+    }
+
+    #[test]
+    fn normalizes_level_aliases() {
+        let aliases = HashMap::from([("OOPS".to_string(), ErrorLevel::Alert)]);
+        for (name, expected) in [
+            ("WARN", ErrorLevel::Warning),
+            ("info", ErrorLevel::Info),
+            ("Fatal", ErrorLevel::Critical),
+            ("ERR", ErrorLevel::Error),
+            ("trace", ErrorLevel::Debug),
+            ("3", ErrorLevel::Error),
+            ("0", ErrorLevel::Emergency),
+            ("oops", ErrorLevel::Alert),
+            ("whatever", ErrorLevel::Unknown),
+            ("8", ErrorLevel::Unknown),
+        ] {
+            let header = format!("[{}] - 2024-04-06T08:48:24Z", name);
+            let (level, _) = parse_header(&header, &aliases).unwrap();
+            assert_eq!(level, expected, "{}", name);
+        }
+    }
+
+    fn log_with(fields: &[&str]) -> Log {
+        let lines: Vec<String> = ["[Error] - 2024-04-06T08:48:24Z"]
+            .iter()
+            .chain(fields)
+            .chain(&["Message:", "Something broke."])
+            .map(|line| line.to_string())
+            .collect();
+        create_log(&lines, &HashMap::new()).unwrap()
+    }
+
+    #[test]
+    fn keeps_colons_in_custom_values() {
+        let log = log_with(&[
+            "Request URL: https://example.com:8443/x?a=b",
+            "Path: C:\\inetpub\\wwwroot\\app.php",
+            "Started At: 2024-04-06T08:48:24+02:00",
+            "Empty:",
+        ]);
+        assert_eq!(log.custom["Request URL"], "https://example.com:8443/x?a=b");
+        assert_eq!(log.custom["Path"], "C:\\inetpub\\wwwroot\\app.php");
+        assert_eq!(log.custom["Started At"], "2024-04-06T08:48:24+02:00");
+        assert_eq!(log.custom["Empty"], "");
+        assert_eq!(log.message, "Something broke.");
+    }
+
+    #[test]
+    fn keeps_colons_in_known_fields() {
+        let log = log_with(&[
+            "File: C:\\Users\\app\\src\\DatabaseService.php",
+            "Function: App\\Services\\DatabaseService::query()",
+            "Category: http://internal:8080",
+            "Branch: a:b:c",
+        ]);
+        assert_eq!(log.file, "C:\\Users\\app\\src\\DatabaseService.php");
+        assert_eq!(log.function, "App\\Services\\DatabaseService::query()");
+        assert_eq!(log.category, "http://internal:8080");
+        assert_eq!(log.branch, "a:b:c");
+        assert!(log.custom.is_empty());
+    }
+
+    #[test]
+    fn maps_json_lines_onto_logs() {
+        let body = concat!(
+            r#"{"level":"warn","timestamp":"2024-04-06T08:48:24Z","message":"Slow query","file":"db.js","line":42,"category":"Database","requestId":"abc","durationMs":1532,"tags":["a","b"]}"#,
+            "\n\n",
+            r#"{"level":3,"timestamp":"2024-04-06T08:48:25Z","message":"Failed"}"#,
+            "\r\n",
+            r#"{"level":"error","message":"No timestamp"}"#,
+            "\n",
+            r#"not json"#,
+        );
+        let mut parser = BodyParser::new("application/x-ndjson; charset=utf-8", 1024);
+        let mut entries = parser.feed(body.as_bytes());
+        entries.extend(parser.finish());
+        let logs: Vec<Result<Log, RejectReason>> = entries
+            .iter()
+            .map(|entry| parser.create_log(entry.as_ref().unwrap(), &HashMap::new()))
+            .collect();
+
+        assert_eq!(logs.len(), 4);
+        let slow = logs[0].as_ref().unwrap();
+        assert_eq!(slow.level, ErrorLevel::Warning);
+        assert_eq!(slow.message, "Slow query");
+        assert_eq!(slow.file, "db.js");
+        assert_eq!(slow.line, Some(42));
+        assert_eq!(slow.category, "Database");
+        assert_eq!(slow.custom["requestId"], "abc");
+        assert_eq!(slow.custom["durationMs"], "1532");
+        assert_eq!(slow.custom["tags"], r#"["a","b"]"#);
+        assert_eq!(logs[1].as_ref().unwrap().level, ErrorLevel::Error);
+        assert_eq!(logs[2].as_ref().unwrap_err(), &RejectReason::BadTimestamp);
+        assert_eq!(logs[3].as_ref().unwrap_err(), &RejectReason::InvalidJson);
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        assert_eq!(
+            rejections(include_str!("../test/bad-header.log")),
+            vec![
+                (0, RejectReason::BadHeader),
+                (1, RejectReason::BadHeader),
+                (2, RejectReason::BadHeader),
+                (3, RejectReason::BadHeader),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_timestamps() {
+        assert_eq!(
+            rejections(include_str!("../test/bad-timestamp.log")),
+            vec![
+                (0, RejectReason::BadTimestamp),
+                (1, RejectReason::BadTimestamp),
+                (2, RejectReason::BadTimestamp),
+                (3, RejectReason::BadTimestamp),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unterminated_entries() {
+        let body = include_str!("../test/bad-eol.log");
+        let logs = parse(body, body.len());
+        assert!(logs[0].is_ok());
+        assert_eq!(
+            logs[1].as_ref().unwrap_err(),
+            &(1, RejectReason::MissingEol)
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_entries_and_continues() {
+        let mut parser = EntryParser::new(300);
+        let body = format!(
+            "[Error] - 2024-04-06T08:48:24Z\nMessage:\n{}\n---[EOL]---\n{}",
+            "x".repeat(400),
+            GOOD_FIXTURES[0].split(EOL).next().unwrap().to_string() + EOL
+        );
+        let mut entries = parser.feed(body.as_bytes());
+        entries.extend(parser.finish());
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[0].as_ref().unwrap_err().reason,
+            RejectReason::EntryTooLarge
+        );
+        assert_eq!(entries[1].as_ref().unwrap().index, 1);
+    }
+
+    fn warning_kinds(content_type: &str, body: &str) -> Vec<(usize, WarningKind)> {
+        let mut parser = BodyParser::new(content_type, 1024);
+        let mut entries = parser.feed(body.as_bytes());
+        entries.extend(parser.finish());
+        entries
+            .iter()
+            .flat_map(|entry| {
+                let entry = entry.as_ref().unwrap();
+                let log = parser.create_log(entry, &HashMap::new()).ok();
+                parser.warnings(entry, log.as_ref())
+            })
+            .map(|warning| (warning.index, warning.kind))
+            .collect()
+    }
+
+    #[test]
+    fn warns_about_likely_mistakes() {
+        let body = concat!(
+            "[Informational] - 2024-04-06T08:48:24Z\nMessage:\nFine\n---[EOL]---\n",
+            "[Oops] - 2024-04-06T08:48:24Z\nUid: 42\nEnv: prod\n---[EOL]---\n",
+            "[Error] -\nMessage:\nNo timestamp\n---[EOL]---\n",
+            "[Error] - 2024-04-06T08:48:24Z\nMessage:\n---[EOL]---\n",
+        );
+        assert_eq!(
+            warning_kinds("text/plain", body),
+            vec![
+                (1, WarningKind::UnknownLevel),
+                (1, WarningKind::MissingMessage),
+                (1, WarningKind::ReservedCustomKey),
+                (1, WarningKind::ReservedCustomKey),
+                (2, WarningKind::MissingTimestamp),
+                (3, WarningKind::MissingMessage),
+            ]
+        );
+
+        let body = concat!(
+            r#"{"level":"info","timestamp":"2024-04-06T08:48:24Z","message":"Fine"}"#,
+            "\n",
+            r#"{"level":"info","message":"No timestamp"}"#,
+            "\n",
+            r#"{"level":"info","timestamp":"2024-04-06T08:48:24Z","custom":"x"}"#,
+        );
+        assert_eq!(
+            warning_kinds("application/x-ndjson", body),
+            vec![
+                (1, WarningKind::MissingTimestamp),
+                (2, WarningKind::MissingMessage),
+                (2, WarningKind::ReservedCustomKey),
+            ]
+        );
+    }
+}
@@ -0,0 +1,328 @@
+use anyhow::{anyhow, Result};
+use axum::body::Body;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::NaiveDate;
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncSeekExt, BufReader};
+use tokio::sync::mpsc;
+
+use crate::{ErrorLevel, Log};
+
+/// Logs per page when the request doesn't set a limit.
+pub const LIMIT: usize = 1000;
+pub const MAX_LIMIT: usize = 10_000;
+
+/// Lines read ahead of a slow client.
+const BUFFER_SIZE: usize = 64;
+
+/// Conditions on the fields of a log. Every condition that is set must match,
+/// and a list matches any of its values. Text is compared ignoring case.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    pub levels: Vec<ErrorLevel>,
+    pub envs: Vec<String>,
+    pub categories: Vec<String>,
+    pub branch: Option<String>,
+    pub custom: HashMap<String, String>,
+}
+
+fn list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+impl Filter {
+    /// Reads a filter from query parameters: comma separated `levels`, `envs`
+    /// and `categories`, `branch`, and `custom.<key>` for custom data. Other
+    /// parameters are rejected unless they are `allowed`.
+    pub fn from_params(params: &HashMap<String, String>, allowed: &[&str]) -> Result<Filter> {
+        let mut filter = Filter::default();
+        for (name, value) in params {
+            match name.as_str() {
+                "levels" => {
+                    filter.levels = list(value)
+                        .iter()
+                        .map(|level| {
+                            ErrorLevel::from_filter(level)
+                                .ok_or_else(|| anyhow!("Unknown level \"{}\"", level))
+                        })
+                        .collect::<Result<Vec<ErrorLevel>>>()?;
+                }
+                "envs" => filter.envs = list(value),
+                "categories" => filter.categories = list(value),
+                "branch" => filter.branch = Some(value.clone()),
+                name if allowed.contains(&name) => {}
+                name => match name.strip_prefix("custom.") {
+                    Some(key) if !key.is_empty() => {
+                        filter.custom.insert(key.to_string(), value.clone());
+                    }
+                    _ => return Err(anyhow!("Unknown parameter \"{}\"", name)),
+                },
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn matches(&self, log: &Log) -> bool {
+        let any = |values: &[String], field: &str| {
+            values.is_empty() || values.iter().any(|value| value.eq_ignore_ascii_case(field))
+        };
+        (self.levels.is_empty() || self.levels.contains(&log.level))
+            && any(&self.envs, &log.env)
+            && any(&self.categories, &log.category)
+            && self
+                .branch
+                .as_ref()
+                .is_none_or(|branch| branch.eq_ignore_ascii_case(&log.branch))
+            && self.custom.iter().all(|(key, value)| {
+                log.custom
+                    .get(key)
+                    .is_some_and(|custom| custom.eq_ignore_ascii_case(value))
+            })
+    }
+}
+
+/// Where the next page starts: a line of a day's ledger.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Cursor {
+    date: NaiveDate,
+    offset: u64,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap())
+    }
+
+    pub fn decode(cursor: &str) -> Option<Cursor> {
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()
+    }
+}
+
+/// Streams the logs of an app written from `from` to `to` that match the
+/// filter as NDJSON, reading the ledgers a line at a time. When more logs
+/// match than the limit, the stream ends with `{"cursor":"..."}` instead of
+/// the next log.
+pub fn stream(
+    app_path: PathBuf,
+    (from, to): (NaiveDate, NaiveDate),
+    filter: Filter,
+    cursor: Option<Cursor>,
+    limit: usize,
+) -> Body {
+    let (sender, receiver) = mpsc::channel::<io::Result<String>>(BUFFER_SIZE);
+    tokio::spawn(async move {
+        let result = send_logs(&app_path, (from, to), &filter, cursor, limit, &sender).await;
+        if let Err(error) = result {
+            // Ends the response with an error, so the client sees it was cut short.
+            let _ = sender.send(Err(io::Error::other(error.to_string()))).await;
+        }
+    });
+    Body::from_stream(stream::unfold(receiver, |mut receiver| async move {
+        let line = receiver.recv().await?;
+        Some((line, receiver))
+    }))
+}
+
+async fn send_logs(
+    app_path: &Path,
+    (from, to): (NaiveDate, NaiveDate),
+    filter: &Filter,
+    cursor: Option<Cursor>,
+    limit: usize,
+    sender: &mpsc::Sender<io::Result<String>>,
+) -> Result<()> {
+    let (start, mut offset) = match cursor {
+        Some(cursor) if cursor.date >= from => (cursor.date, cursor.offset),
+        _ => (from, 0),
+    };
+    let days = ledger_dates(&app_path.join("ledgers"), (start, to)).await?;
+    let mut sent = 0;
+    for day in days {
+        if day != start {
+            offset = 0;
+        }
+        let path = app_path
+            .join("ledgers")
+            .join(format!("{}.jsonl", day.format("%Y-%m-%d")));
+        // Retention may have removed the day since it was listed.
+        let Ok(mut file) = File::open(&path).await else {
+            continue;
+        };
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line).await?;
+            // The last line may still be being written.
+            if read == 0 || !line.ends_with('\n') {
+                break;
+            }
+            let start = offset;
+            offset += read as u64;
+
+            let Ok(log) = serde_json::from_str::<Log>(&line) else {
+                continue;
+            };
+            if !filter.matches(&log) {
+                continue;
+            }
+            if sent == limit {
+                let cursor = Cursor {
+                    date: day,
+                    offset: start,
+                };
+                let line = json!({ "cursor": cursor.encode() }).to_string() + "\n";
+                let _ = sender.send(Ok(line)).await;
+                return Ok(());
+            }
+            if sender.send(Ok(line.clone())).await.is_err() {
+                // The client went away.
+                return Ok(());
+            }
+            sent += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Lists the days from `from` to `to` that have a ledger, oldest first. Days
+/// without logs have no ledger, so long ranges are mostly empty.
+async fn ledger_dates(
+    ledgers: &Path,
+    (from, to): (NaiveDate, NaiveDate),
+) -> Result<Vec<NaiveDate>> {
+    let mut entries = match tokio::fs::read_dir(ledgers).await {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error.into()),
+    };
+    let mut dates = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some(date) = name
+            .strip_suffix(".jsonl")
+            .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok())
+        else {
+            continue;
+        };
+        if date >= from && date <= to {
+            dates.push(date);
+        }
+    }
+    dates.sort();
+    Ok(dates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::empty_log;
+
+    #[test]
+    fn reads_filters_from_params() {
+        let params: HashMap<String, String> = [
+            ("levels", "error, warn"),
+            ("envs", "prod"),
+            ("branch", "main"),
+            ("custom.user_id", "42"),
+            ("limit", "10"),
+        ]
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+        let filter = Filter::from_params(&params, &["limit"]).unwrap();
+        assert_eq!(filter.levels, vec![ErrorLevel::Error, ErrorLevel::Warning]);
+        assert_eq!(filter.envs, vec!["prod"]);
+
+        let mut log = empty_log();
+        log.level = ErrorLevel::Warning;
+        log.env = "Prod".to_string();
+        log.branch = "main".to_string();
+        log.custom.insert("user_id".to_string(), "42".to_string());
+        assert!(filter.matches(&log));
+        log.custom.insert("user_id".to_string(), "7".to_string());
+        assert!(!filter.matches(&log));
+        assert!(Filter::default().matches(&log));
+
+        let error = Filter::from_params(&params, &[]).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown parameter \"limit\"");
+        let params = HashMap::from([("levels".to_string(), "loud".to_string())]);
+        let error = Filter::from_params(&params, &[]).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown level \"loud\"");
+    }
+
+    #[tokio::test]
+    async fn streams_pages_across_days() {
+        let app_path =
+            std::env::temp_dir().join(format!("lumberjack-ledger-{}", uuid::Uuid::now_v7()));
+        std::fs::create_dir_all(app_path.join("ledgers")).unwrap();
+        for (date, levels) in [
+            ("2024-04-05", vec![ErrorLevel::Error]),
+            ("2024-04-06", vec![ErrorLevel::Error, ErrorLevel::Info]),
+            ("2024-04-08", vec![ErrorLevel::Error, ErrorLevel::Error]),
+        ] {
+            let mut ledger = String::new();
+            for level in levels {
+                let mut log = empty_log();
+                log.level = level;
+                log.message = date.to_string();
+                ledger.push_str(&serde_json::to_string(&log).unwrap());
+                ledger.push('\n');
+            }
+            std::fs::write(
+                app_path.join("ledgers").join(format!("{}.jsonl", date)),
+                ledger,
+            )
+            .unwrap();
+        }
+
+        let filter = Filter {
+            levels: vec![ErrorLevel::Error],
+            ..Filter::default()
+        };
+        let range = ("2024-04-06".parse().unwrap(), "2024-04-08".parse().unwrap());
+        let mut pages = Vec::new();
+        let mut cursor = None;
+        loop {
+            let body = stream(app_path.clone(), range, filter.clone(), cursor, 2);
+            let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+            let lines: Vec<serde_json::Value> = String::from_utf8(body.to_vec())
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            cursor = lines
+                .last()
+                .and_then(|line| line["cursor"].as_str())
+                .map(|next| Cursor::decode(next).unwrap());
+            pages.push(
+                lines
+                    .iter()
+                    .filter_map(|line| line["message"].as_str().map(String::from))
+                    .collect::<Vec<String>>(),
+            );
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(
+            pages,
+            vec![vec!["2024-04-06", "2024-04-08"], vec!["2024-04-08"]]
+        );
+
+        std::fs::remove_dir_all(&app_path).unwrap();
+    }
+}
@@ -0,0 +1,396 @@
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use chrono::{DateTime, SecondsFormat, Utc};
+use prost::Message;
+use serde_json::Value;
+
+use crate::ingest::empty_log;
+use crate::{ErrorLevel, Log};
+
+// Loki's push API schema, from pkg/push/push.proto. Timestamps are
+// google.protobuf.Timestamp messages.
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PushRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub streams: Vec<StreamAdapter>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct StreamAdapter {
+    #[prost(string, tag = "1")]
+    pub labels: String,
+    #[prost(message, repeated, tag = "2")]
+    pub entries: Vec<EntryAdapter>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct EntryAdapter {
+    #[prost(message, optional, tag = "1")]
+    pub timestamp: Option<Timestamp>,
+    #[prost(string, tag = "2")]
+    pub line: String,
+    #[prost(message, repeated, tag = "3")]
+    pub structured_metadata: Vec<LabelPairAdapter>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct LabelPairAdapter {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Timestamp {
+    #[prost(int64, tag = "1")]
+    pub seconds: i64,
+    #[prost(int32, tag = "2")]
+    pub nanos: i32,
+}
+
+/// A stream and its lines, decoded from either encoding.
+pub struct Stream {
+    pub labels: Vec<(String, String)>,
+    pub entries: Vec<StreamEntry>,
+}
+
+pub struct StreamEntry {
+    pub timestamp: DateTime<Utc>,
+    pub line: String,
+    pub metadata: Vec<(String, String)>,
+}
+
+/// Logs of one stream, which its labels tie to an app.
+pub struct StreamBatch {
+    pub app: Option<String>,
+    pub env: Option<String>,
+    pub logs: Vec<Log>,
+}
+
+const APP_LABELS: [&str; 3] = ["app", "service_name", "job"];
+const ENV_LABELS: [&str; 2] = ["env", "environment"];
+const LEVEL_LABELS: [&str; 3] = ["level", "detected_level", "severity"];
+
+/// Decodes a push request. Loki treats every body that isn't JSON as a snappy
+/// compressed protobuf, and so does this.
+pub fn decode(content_type: &str, body: &[u8]) -> Result<Vec<Stream>> {
+    match content_type.split(';').next().unwrap_or("").trim() {
+        "application/json" => streams_from_json(&serde_json::from_slice(body)?),
+        _ => {
+            let body = snap::raw::Decoder::new()
+                .decompress_vec(body)
+                .map_err(|error| anyhow!("Body is not snappy compressed: {}", error))?;
+            PushRequest::decode(body.as_slice())?
+                .streams
+                .into_iter()
+                .map(stream_from_protobuf)
+                .collect()
+        }
+    }
+}
+
+fn stream_from_protobuf(stream: StreamAdapter) -> Result<Stream> {
+    Ok(Stream {
+        labels: parse_labels(&stream.labels)?,
+        entries: stream
+            .entries
+            .into_iter()
+            .map(|entry| {
+                let timestamp = entry
+                    .timestamp
+                    .and_then(|ts| DateTime::from_timestamp(ts.seconds, ts.nanos as u32))
+                    .unwrap_or_else(Utc::now);
+                StreamEntry {
+                    timestamp,
+                    line: entry.line,
+                    metadata: entry
+                        .structured_metadata
+                        .into_iter()
+                        .map(|pair| (pair.name, pair.value))
+                        .collect(),
+                }
+            })
+            .collect(),
+    })
+}
+
+fn streams_from_json(value: &Value) -> Result<Vec<Stream>> {
+    let streams = value
+        .get("streams")
+        .and_then(|streams| streams.as_array())
+        .ok_or_else(|| anyhow!("Body has no streams array"))?;
+    let mut decoded = Vec::new();
+    for stream in streams {
+        let labels = stream
+            .get("stream")
+            .and_then(|labels| labels.as_object())
+            .map(|labels| {
+                labels
+                    .iter()
+                    .map(|(name, value)| (name.clone(), json_string(value)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut entries = Vec::new();
+        for value in stream
+            .get("values")
+            .and_then(|values| values.as_array())
+            .map(|values| values.as_slice())
+            .unwrap_or_default()
+        {
+            let invalid = || anyhow!("Stream values must be [\"<unix nanoseconds>\", \"<line>\"]");
+            let value = value.as_array().ok_or_else(invalid)?;
+            let nanos: i64 = match value.first() {
+                Some(Value::String(nanos)) => nanos.parse().map_err(|_| invalid())?,
+                _ => return Err(invalid()),
+            };
+            let line = value
+                .get(1)
+                .and_then(|line| line.as_str())
+                .ok_or_else(invalid)?;
+            let metadata = value
+                .get(2)
+                .and_then(|metadata| metadata.as_object())
+                .map(|metadata| {
+                    metadata
+                        .iter()
+                        .map(|(name, value)| (name.clone(), json_string(value)))
+                        .collect()
+                })
+                .unwrap_or_default();
+            entries.push(StreamEntry {
+                timestamp: DateTime::from_timestamp_nanos(nanos),
+                line: line.to_string(),
+                metadata,
+            });
+        }
+        decoded.push(Stream { labels, entries });
+    }
+    Ok(decoded)
+}
+
+fn json_string(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Parses a label set in Prometheus' `{name="value", ...}` syntax.
+pub fn parse_labels(labels: &str) -> Result<Vec<(String, String)>> {
+    let invalid = || anyhow!("Invalid label set {}", labels);
+    let inner = labels
+        .trim()
+        .strip_prefix('{')
+        .and_then(|labels| labels.strip_suffix('}'))
+        .ok_or_else(invalid)?;
+
+    let mut pairs = Vec::new();
+    let mut chars = inner.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+        let mut name = String::new();
+        while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || *c == '_') {
+            name.push(c);
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if name.is_empty() || chars.next() != Some('=') {
+            return Err(invalid());
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.next() != Some('"') {
+            return Err(invalid());
+        }
+        let mut value = String::new();
+        loop {
+            match chars.next().ok_or_else(invalid)? {
+                '"' => break,
+                '\\' => match chars.next().ok_or_else(invalid)? {
+                    'n' => value.push('\n'),
+                    't' => value.push('\t'),
+                    c => value.push(c),
+                },
+                c => value.push(c),
+            }
+        }
+        pairs.push((name, value));
+    }
+    Ok(pairs)
+}
+
+/// Maps every line of a stream onto a `Log`. The `app`, `env`, `category` and
+/// `level` labels fill in the matching fields and every other label and
+/// structured metadata pair is stored in `custom`.
+pub fn stream_batch(stream: Stream) -> StreamBatch {
+    let label = |names: &[&str]| {
+        names.iter().find_map(|name| {
+            stream
+                .labels
+                .iter()
+                .find(|(label, value)| label == name && !value.is_empty())
+                .map(|(_, value)| value.clone())
+        })
+    };
+    let app = label(&APP_LABELS);
+    let env = label(&ENV_LABELS);
+    let category = label(&["category"]).unwrap_or_default();
+    let level = label(&LEVEL_LABELS);
+    let custom: Vec<&(String, String)> = stream
+        .labels
+        .iter()
+        .filter(|(name, _)| {
+            !APP_LABELS.contains(&name.as_str())
+                && !ENV_LABELS.contains(&name.as_str())
+                && !LEVEL_LABELS.contains(&name.as_str())
+                && name != "category"
+        })
+        .collect();
+
+    let logs = stream
+        .entries
+        .iter()
+        .map(|entry| {
+            let mut new_log = empty_log();
+            new_log.timestamp = entry.timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true);
+            new_log.message = entry.line.clone();
+            new_log.category = category.clone();
+            let metadata = entry
+                .metadata
+                .iter()
+                .filter(|(name, _)| !LEVEL_LABELS.contains(&name.as_str()));
+            for (name, value) in custom.iter().copied().chain(metadata) {
+                new_log.custom.insert(name.clone(), value.clone());
+            }
+            let level = entry
+                .metadata
+                .iter()
+                .find(|(name, _)| LEVEL_LABELS.contains(&name.as_str()))
+                .map(|(_, value)| value)
+                .or(level.as_ref());
+            new_log.level = level
+                .and_then(|level| ErrorLevel::from_alias(level))
+                .unwrap_or(ErrorLevel::Unknown);
+            new_log
+        })
+        .collect();
+
+    StreamBatch { app, env, logs }
+}
+
+/// Shippers send the key as a bearer token or a basic auth password rather
+/// than as the bare `Authorization` value.
+pub fn api_key(authorization: &str) -> String {
+    if let Some(token) = authorization.strip_prefix("Bearer ") {
+        return token.trim().to_string();
+    }
+    if let Some(credentials) = authorization.strip_prefix("Basic ") {
+        if let Some(password) = base64::engine::general_purpose::STANDARD
+            .decode(credentials.trim())
+            .ok()
+            .and_then(|credentials| String::from_utf8(credentials).ok())
+            .and_then(|credentials| {
+                credentials
+                    .split_once(':')
+                    .map(|(_, password)| password.to_string())
+            })
+        {
+            return password;
+        }
+    }
+    authorization.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_label_sets() {
+        let labels = parse_labels(r#"{app="web", env = "prod",path="C:\\logs\"x\""}"#).unwrap();
+        assert_eq!(
+            labels,
+            vec![
+                ("app".to_string(), "web".to_string()),
+                ("env".to_string(), "prod".to_string()),
+                ("path".to_string(), r#"C:\logs"x""#.to_string()),
+            ]
+        );
+        assert!(parse_labels("{}").unwrap().is_empty());
+        assert!(parse_labels(r#"{app="web"#).is_err());
+        assert!(parse_labels("app=web").is_err());
+    }
+
+    #[test]
+    fn maps_snappy_protobuf_pushes() {
+        let request = PushRequest {
+            streams: vec![StreamAdapter {
+                labels: r#"{app="Billing API", env="production", category="jobs", level="warn", host="web-1"}"#
+                    .to_string(),
+                entries: vec![EntryAdapter {
+                    timestamp: Some(Timestamp {
+                        seconds: 1_712_393_304,
+                        nanos: 500_000_000,
+                    }),
+                    line: "Invoice queue is backing up".to_string(),
+                    structured_metadata: vec![LabelPairAdapter {
+                        name: "trace_id".to_string(),
+                        value: "abc".to_string(),
+                    }],
+                }],
+            }],
+        };
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&request.encode_to_vec())
+            .unwrap();
+
+        let mut streams = decode("application/x-protobuf", &body).unwrap();
+        let batch = stream_batch(streams.remove(0));
+        assert_eq!(batch.app.as_deref(), Some("Billing API"));
+        assert_eq!(batch.env.as_deref(), Some("production"));
+        let log = &batch.logs[0];
+        assert_eq!(log.level, ErrorLevel::Warning);
+        assert_eq!(log.category, "jobs");
+        assert_eq!(log.timestamp, "2024-04-06T08:48:24.500Z");
+        assert_eq!(log.message, "Invoice queue is backing up");
+        assert_eq!(log.custom["host"], "web-1");
+        assert_eq!(log.custom["trace_id"], "abc");
+        assert!(!log.custom.contains_key("app"));
+    }
+
+    #[test]
+    fn maps_json_pushes() {
+        let body = json!({
+            "streams": [{
+                "stream": { "job": "worker" },
+                "values": [
+                    ["1712393304000000000", "started"],
+                    ["1712393305000000000", "failed", { "level": "error" }]
+                ]
+            }]
+        });
+        let mut streams = decode("application/json", body.to_string().as_bytes()).unwrap();
+        let batch = stream_batch(streams.remove(0));
+        assert_eq!(batch.app.as_deref(), Some("worker"));
+        assert_eq!(batch.env, None);
+        assert_eq!(batch.logs[0].level, ErrorLevel::Unknown);
+        assert_eq!(batch.logs[0].timestamp, "2024-04-06T08:48:24Z");
+        assert_eq!(batch.logs[1].level, ErrorLevel::Error);
+        assert_eq!(batch.logs[1].message, "failed");
+
+        let body = json!({ "streams": [{ "stream": {}, "values": [[1712393304, "x"]] }] });
+        assert!(decode("application/json", body.to_string().as_bytes()).is_err());
+    }
+
+    #[test]
+    fn reads_keys_from_bearer_and_basic_auth() {
+        assert_eq!(api_key("Bearer abc"), "abc");
+        assert_eq!(api_key("Basic dXNlcjphYmM="), "abc");
+        assert_eq!(api_key("abc"), "abc");
+    }
+}
@@ -1,44 +1,71 @@
 use anyhow::{Error, Result};
 use askama_axum::Template;
+use async_compression::tokio::bufread::{
+    BrotliDecoder, DeflateDecoder, GzipDecoder, ZlibDecoder, ZstdDecoder,
+};
 use axum::{
     body::Body,
     extract::Path as PathExtractor,
+    extract::Query as QueryExtractor,
+    extract::WebSocketUpgrade,
     http::{Request, StatusCode},
     response::{IntoResponse, Response},
     routing::{delete, get, post},
     Router,
 };
 use axum_macros::debug_handler;
-use chrono::DateTime;
-use core::iter::Peekable;
+use chrono::{DateTime, NaiveDate, Utc};
+use futures_util::StreamExt;
 use lazy_static::lazy_static;
 use owo_colors::OwoColorize;
 use rand::Rng;
 use rand::{distributions::Alphanumeric, thread_rng};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::{self, Display};
 use std::fs;
 use std::fs::read_dir;
 use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
-use std::slice::Iter;
+use std::pin::Pin;
+use std::str::FromStr;
 use std::sync::Mutex;
 use std::{
     collections::HashMap,
     io::{BufReader, BufWriter, Read, Seek, SeekFrom},
 };
-use std::{
-    env,
-    fmt::{self, Display},
-};
-use tokio::process::Command;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt};
+use tokio_util::io::StreamReader;
 use tower_http::services::ServeFile;
-use uuid::Uuid;
 
-#[macro_use]
-extern crate dotenv_codegen;
+use crate::archive::ArchiveStore;
+use crate::config::{CliCommand, Config};
+use crate::ingest::{
+    BodyParser, Entry, IngestReport, RejectedEntry, ValidatedLog, ValidationReport,
+};
+use crate::retention::Trigger;
+
+mod archive;
+mod config;
+mod gelf;
+mod idempotency;
+mod ingest;
+mod ledger;
+mod loki;
+mod notify;
+mod otlp;
+mod query;
+mod retention;
+mod s3;
+mod search;
+mod syslog;
+mod tail;
 
 static VERSION: u32 = 1;
+/// How long requests get to finish after a shutdown signal before search
+/// indexes are committed and the process exits.
+const SHUTDOWN_GRACE: std::time::Duration = std::time::Duration::from_secs(5);
 
 fn generate_random_string(len: usize) -> String {
     let rng = thread_rng();
@@ -52,23 +79,7 @@ fn generate_random_string(len: usize) -> String {
 
 fn to_kebab_case(input: &str) -> String {
     let trimmed = input.trim();
-    let kebab_case = trimmed.to_lowercase().replace(" ", "-");
-    kebab_case
-}
-
-#[derive(Clone, Debug, Serialize)]
-enum Rentention {
-    DELETE,
-    ARCHIVE,
-}
-
-impl Display for Rentention {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            Rentention::DELETE => write!(f, "DELETE"),
-            Rentention::ARCHIVE => write!(f, "ARCHIVE"),
-        }
-    }
+    trimmed.to_lowercase().replace(" ", "-")
 }
 
 struct AppError(anyhow::Error);
@@ -79,7 +90,7 @@ impl IntoResponse for AppError {
     }
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 enum ErrorLevel {
     Emergency,
     Alert,
@@ -92,7 +103,86 @@ enum ErrorLevel {
     Unknown,
 }
 
-#[derive(Clone, Debug, Serialize)]
+impl ErrorLevel {
+    /// Every level a log can be routed by, from most to least severe.
+    const ROUTABLE: [ErrorLevel; 8] = [
+        ErrorLevel::Emergency,
+        ErrorLevel::Alert,
+        ErrorLevel::Critical,
+        ErrorLevel::Error,
+        ErrorLevel::Warning,
+        ErrorLevel::Notice,
+        ErrorLevel::Info,
+        ErrorLevel::Debug,
+    ];
+
+    fn name(&self) -> &'static str {
+        match *self {
+            ErrorLevel::Emergency => "emergency",
+            ErrorLevel::Alert => "alert",
+            ErrorLevel::Critical => "critical",
+            ErrorLevel::Error => "error",
+            ErrorLevel::Warning => "warning",
+            ErrorLevel::Notice => "notice",
+            ErrorLevel::Info => "info",
+            ErrorLevel::Debug => "debug",
+            ErrorLevel::Unknown => "unknown",
+        }
+    }
+
+    /// Maps a level name as emitted by common logging libraries (syslog,
+    /// Monolog, log4j, Python logging, ...) or an RFC 5424 numeric severity
+    /// onto a level.
+    fn from_alias(name: &str) -> Option<ErrorLevel> {
+        let level = match name.trim().to_uppercase().as_str() {
+            "EMERGENCY" | "EMERG" | "PANIC" | "0" => ErrorLevel::Emergency,
+            "ALERT" | "1" => ErrorLevel::Alert,
+            "CRITICAL" | "CRIT" | "FATAL" | "2" => ErrorLevel::Critical,
+            "ERROR" | "ERR" | "SEVERE" | "3" => ErrorLevel::Error,
+            "WARNING" | "WARN" | "4" => ErrorLevel::Warning,
+            "NOTICE" | "5" => ErrorLevel::Notice,
+            "INFORMATIONAL" | "INFORMATION" | "INFO" | "6" => ErrorLevel::Info,
+            "DEBUG" | "TRACE" | "VERBOSE" | "FINE" | "FINER" | "FINEST" | "7" => ErrorLevel::Debug,
+            _ => return None,
+        };
+        Some(level)
+    }
+
+    /// Reads a level named when looking logs up, where unlike ingestion
+    /// `unknown` is a level of its own.
+    fn from_filter(name: &str) -> Option<ErrorLevel> {
+        if name.trim().eq_ignore_ascii_case("unknown") {
+            return Some(ErrorLevel::Unknown);
+        }
+        ErrorLevel::from_alias(name)
+    }
+}
+
+impl Display for ErrorLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorLevel::Emergency => write!(f, "Emergency"),
+            ErrorLevel::Alert => write!(f, "Alert"),
+            ErrorLevel::Critical => write!(f, "Critical"),
+            ErrorLevel::Error => write!(f, "Error"),
+            ErrorLevel::Warning => write!(f, "Warning"),
+            ErrorLevel::Notice => write!(f, "Notice"),
+            ErrorLevel::Info => write!(f, "Info"),
+            ErrorLevel::Debug => write!(f, "Debug"),
+            ErrorLevel::Unknown => write!(f, "Unknown"),
+        }
+    }
+}
+
+impl FromStr for ErrorLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ErrorLevel::from_alias(s).ok_or_else(|| anyhow::anyhow!("unknown log level \"{}\"", s))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 struct Log {
     uid: String,
     level: ErrorLevel,
@@ -107,16 +197,6 @@ struct Log {
     category: String,
 }
 
-enum LogSection {
-    Message,
-    File,
-    Function,
-    Line,
-    Custom,
-    Branch,
-    Category,
-}
-
 // This enables using `?` on functions that return `Result<_, anyhow::Error>` to turn them into
 // `Result<_, AppError>`. That way you don't need to do that manually.
 impl<E> From<E> for AppError
@@ -129,32 +209,55 @@ where
 }
 
 lazy_static! {
-    static ref CONFIG: Mutex<HashMap<String, String>> = Mutex::new({
-        let mut m = HashMap::new();
-        m.insert(
-            "storage_path".to_string(),
-            dotenv!("STORAGE_PATH").to_string(),
-        );
-        m.insert("master_key".to_string(), dotenv!("MASTER_KEY").to_string());
-        m.insert("port".to_string(), dotenv!("PORT").to_string());
-        m.insert(
-            "mode".to_string(),
-            dotenv!("MODE").to_string().to_lowercase(),
-        );
-        m.insert(
-            "days_retained".to_string(),
-            dotenv!("DAYS_RETAINED").to_string(),
-        );
-        m
-    });
-    static ref KEYS: Mutex<HashMap<String, Vec<String>>> = Mutex::new({
-        let m = HashMap::new();
-        m
-    });
+    static ref CONFIG: Mutex<Config> = Mutex::new(Config::default());
+    static ref KEYS: Mutex<HashMap<String, Vec<String>>> = Mutex::new(HashMap::new());
+    /// Held while appending to or rewriting a ledger so retention never drops
+    /// entries written during a rewrite.
+    static ref LEDGER_LOCK: Mutex<()> = Mutex::new(());
 }
 
 #[tokio::main]
 async fn main() {
+    let (mut config, command) = match Config::load() {
+        Ok(loaded) => loaded,
+        Err(error) => {
+            eprintln!(
+                "{}\n",
+                " Failed to load configuration ".bold().black().on_red()
+            );
+            eprintln!("{:#}", error);
+            std::process::exit(1);
+        }
+    };
+
+    if let CliCommand::Restore { app, from, to } = command {
+        let app = to_kebab_case(&app);
+        let app_path = Path::new(&config.storage_path).join(&app);
+        let restored = archive::restore_days(
+            &ArchiveStore::from_config(&config),
+            &app_path,
+            Path::new(&config.archive_path),
+            from,
+            to.unwrap_or(from),
+        )
+        .await;
+        match restored {
+            Ok(days) => {
+                for day in days {
+                    println!(
+                        "Restored {}/{}: {} entries from {} archive(s), {} already present",
+                        app, day.date, day.restored, day.archives, day.skipped
+                    );
+                }
+                std::process::exit(0);
+            }
+            Err(error) => {
+                eprintln!("Failed to restore {}: {:#}", app, error);
+                std::process::exit(1);
+            }
+        }
+    }
+
     let ascii_name = r#"
 888                             888                       d8b                   888      
 888                             888                       Y8P                   888      
@@ -170,72 +273,54 @@ async fn main() {
 "#;
     println!("{}", ascii_name);
 
-    let mut port = "7777".to_string();
-    {
-        let mut config = CONFIG.lock().unwrap();
-        let mut keychain = KEYS.lock().unwrap();
-
-        if config.get("storage_path").unwrap().is_empty() {
-            config.insert("storage_path".to_string(), "./data".to_string());
-        }
-
-        port = config.get("port").unwrap().to_string();
-        if port.is_empty() {
-            config.insert("port".to_string(), "7777".to_string());
-            port = "7777".to_string();
-        }
-
-        let mode = match config.get("mode").unwrap().as_str() {
-            "delete" => Rentention::DELETE.to_string(),
-            "archive" => Rentention::ARCHIVE.to_string(),
-            _ => Rentention::DELETE.to_string(),
-        };
-        config.insert("mode".to_string(), mode);
-
-        let days_retained = config
-            .get("days_retained")
-            .unwrap()
-            .parse::<u32>()
-            .unwrap_or(14);
-        config.insert("days_retained".to_string(), days_retained.to_string());
+    let port = config.port;
 
+    println!("Storage path:           \"{}\"", config.storage_path);
+    println!("Package version:        \"{}\"", env!("CARGO_PKG_VERSION"));
+    println!("Server listening on:    \"http://0.0.0.0:{}\"", config.port);
+    println!("Rentention:             \"{} days\"", config.days_retained);
+    println!("Mode:                   \"{}\"", config.mode);
+    if config.retention_interval > 0 {
         println!(
-            "Storage path:           \"{}\"",
-            config.get("storage_path").unwrap()
+            "Cleanup interval:       \"{} minutes\"",
+            config.retention_interval
         );
-        println!("Package version:        \"{}\"", env!("CARGO_PKG_VERSION"));
+    } else {
+        println!("Cleanup interval:       \"disabled\"");
+    }
+    for listener in config.syslog.iter() {
         println!(
-            "Server listening on:    \"http://0.0.0.0:{}\"",
-            config.get("port").unwrap()
+            "Syslog listening on:    \"{}://{}\"",
+            listener.protocol, listener.bind
         );
+    }
+    for listener in config.gelf.iter() {
         println!(
-            "Rentention:             \"{} days\"",
-            config.get("days_retained").unwrap()
+            "GELF listening on:      \"{}://{}\"",
+            listener.protocol, listener.bind
         );
+    }
+
+    println!("\nThank you for using Lumberjack!\n");
+
+    if config.master_key.is_empty() {
+        config.master_key = generate_random_string(128);
         println!(
-            "Mode:                   \"{}\"",
-            config.get("mode").unwrap()
+            "{}",
+            " No MASTER_KEY found in the environment or config file. \n"
+                .bold()
+                .black()
+                .on_yellow()
         );
+        println!("We generated a new secure master key for you (you can safely use this token):\n");
+        println!(">> {} <<", config.master_key);
+        println!("\nRestart Lumberjack with this key as the MASTER_KEY environment variable\n");
+    }
 
-        println!("\nThank you for using Lumberjack!\n");
-
-        if config.get("master_key").unwrap().is_empty() {
-            config.insert("master_key".to_string(), generate_random_string(128));
-            println!(
-                "{}",
-                " No MASTER_KEY found in .env file. \n"
-                    .bold()
-                    .black()
-                    .on_yellow()
-            );
-            println!(
-                "We generated a new secure master key for you (you can safely use this token):\n"
-            );
-            println!(">> {} <<", config.get("master_key").unwrap());
-            println!("\nRestart Lumberjack with this key as the MASTER_KEY environment variable\n");
-        }
+    {
+        let mut keychain = KEYS.lock().unwrap();
 
-        let storage_path = Path::new(config.get("storage_path").unwrap());
+        let storage_path = Path::new(&config.storage_path);
         if !storage_path.exists() {
             fs::create_dir_all(storage_path).unwrap_or_else(|_| {
                 panic!(
@@ -245,7 +330,7 @@ async fn main() {
             });
         }
 
-        for path in fs::read_dir(&storage_path).unwrap() {
+        for path in fs::read_dir(storage_path).unwrap() {
             let path = path.unwrap().path();
             let app = path.to_str().unwrap().rsplit_once("/").unwrap().1;
             let keychain_path = path.join("keychain");
@@ -291,16 +376,42 @@ async fn main() {
         }
     }
 
+    notify::start(&config);
+    if let Err(error) = syslog::start(&config).await {
+        eprintln!("{:#}", error);
+        std::process::exit(1);
+    }
+    if let Err(error) = gelf::start(&config).await {
+        eprintln!("{:#}", error);
+        std::process::exit(1);
+    }
+    // Indexes, in the background, logs whose index wasn't committed before the
+    // last stop.
+    if let Err(error) = search::reconcile_all(Path::new(&config.storage_path)) {
+        eprintln!("Failed to reconcile search indexes: {:#}", error);
+    }
+    *CONFIG.lock().unwrap() = config;
+    retention::start(&CONFIG.lock().unwrap());
+
     let app = Router::new()
         .route("/", get(root))
         .route("/logs", post(write_logs))
+        .route("/logs/validate", post(validate_logs))
+        .route("/v1/logs", post(write_otlp_logs))
+        .route("/loki/api/v1/push", post(write_loki_logs))
+        .route("/gelf", post(write_gelf_log))
+        .route("/logs/:app/tail", get(tail_logs))
         .route("/logs/:app/:file", get(stream_log))
+        .route("/api/logs/:app", get(list_logs))
+        .route("/search", post(search_all_logs))
         .route("/search/:app/:file", post(search_logs))
         .route("/size/:app/:file", get(log_size))
         .route("/admin/keys", get(list_keys))
         .route("/admin/keys", post(create_key))
         .route("/admin/keys", delete(delete_key))
         .route("/admin/cleanup", post(cleanup_logs))
+        .route("/admin/retention", get(retention_status))
+        .route("/admin/restore", post(restore_logs))
         .route_service("/static/main.js", ServeFile::new("static/main.js"))
         .route_service("/static/main.css", ServeFile::new("static/main.css"))
         .route_service(
@@ -319,47 +430,99 @@ async fn main() {
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port))
         .await
         .unwrap();
-    axum::serve(listener, app).await.unwrap();
+    let (stop, stopping) = tokio::sync::watch::channel(());
+    let server = tokio::spawn(async move {
+        let mut stopping = stopping;
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async move {
+                let _ = stopping.changed().await;
+            })
+            .await
+    });
+    shutdown_signal().await;
+    let _ = stop.send(());
+    // Tails never end on their own, so requests only get a moment to finish.
+    let _ = tokio::time::timeout(SHUTDOWN_GRACE, server).await;
+    search::shutdown();
+}
+
+/// Waits for Ctrl+C, or SIGTERM from a container runtime.
+async fn shutdown_signal() {
+    let terminate = async {
+        #[cfg(unix)]
+        {
+            let mut terminate =
+                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                    .expect("Failed to listen for SIGTERM");
+            terminate.recv().await;
+        }
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
 }
 
 #[derive(Template)]
 #[template(path = "root.twig.html")]
 struct RootTemplate {
-    apps: HashMap<String, Vec<String>>,
+    apps: Vec<(String, Vec<LogDay>)>,
+}
+
+struct LogDay {
+    date: String,
+    archived: bool,
 }
 
 #[debug_handler]
 async fn root() -> RootTemplate {
-    let mut storage_path = Path::new("").to_path_buf();
-
+    let storage_path;
+    let archive_path;
     {
         let config = CONFIG.lock().unwrap();
-        storage_path = Path::new(config.get("storage_path").unwrap()).to_owned();
+        storage_path = Path::new(&config.storage_path).to_owned();
+        archive_path = Path::new(&config.archive_path).to_owned();
     }
 
     let paths = read_dir(storage_path).unwrap();
-    let mut apps: HashMap<String, Vec<String>> = HashMap::new();
+    let mut apps: Vec<(String, Vec<LogDay>)> = Vec::new();
     for path in paths {
         let path = path.unwrap();
         let path = path.path();
         let app = path.file_name().unwrap().to_str().unwrap().to_string();
-        let logs = read_dir(path.join("ledgers")).unwrap();
-        let mut log_dates: Vec<String> = Vec::new();
-        for log in logs {
-            let log = log.unwrap();
-            let log = log.path();
-            let log = log
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string()
-                .replace(".jsonl", "");
-            log_dates.push(log);
+        let mut log_days: Vec<LogDay> = Vec::new();
+        if let Ok(logs) = read_dir(path.join("ledgers")) {
+            for log in logs {
+                let log = log.unwrap();
+                let log = log.path();
+                let log = log
+                    .file_name()
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_string()
+                    .replace(".jsonl", "");
+                log_days.push(LogDay {
+                    date: log,
+                    archived: false,
+                });
+            }
         }
-        apps.insert(app, log_dates);
+        for date in archive::archived_days(&archive_path, &app) {
+            if !log_days.iter().any(|day| day.date == date) {
+                log_days.push(LogDay {
+                    date,
+                    archived: true,
+                });
+            }
+        }
+        log_days.sort_by(|a, b| b.date.cmp(&a.date));
+        apps.push((app, log_days));
     }
-    RootTemplate { apps: apps }
+    apps.sort_by(|a, b| a.0.cmp(&b.0));
+    RootTemplate { apps }
 }
 
 #[debug_handler]
@@ -380,27 +543,28 @@ async fn create_key(req: Request<Body>) -> Result<Response<Body>, AppError> {
     }
     let app = to_kebab_case(app.unwrap().to_str().unwrap());
 
-    let mut app_path: PathBuf = Path::new("").to_path_buf();
+    let app_path: PathBuf;
     {
         let config = CONFIG.lock().unwrap();
 
-        if key != config.get("master_key").unwrap() {
+        if key != config.master_key {
             return Err(AppError(anyhow::anyhow!(
                 "Invalid Master Authorization key"
             )));
         }
 
-        app_path = Path::new(config.get("storage_path").unwrap()).join(&app);
+        app_path = Path::new(&config.storage_path).join(&app);
         if !app_path.exists() {
             fs::create_dir_all(&app_path)?;
         }
     }
 
-    app_path = app_path.join("keychain");
+    let app_path = app_path.join("keychain");
     let is_new = !app_path.exists();
 
     let app_keys_file = fs::OpenOptions::new()
         .create(true)
+        .truncate(false)
         .write(true)
         .read(true)
         .open(&app_path)?;
@@ -424,7 +588,7 @@ async fn create_key(req: Request<Body>) -> Result<Response<Body>, AppError> {
 
     writer.write_all(&1_u8.to_be_bytes())?;
     let new_key = generate_random_string(64);
-    writer.write_all(&new_key.as_bytes())?;
+    writer.write_all(new_key.as_bytes())?;
 
     writer.flush()?;
 
@@ -440,7 +604,7 @@ async fn create_key(req: Request<Body>) -> Result<Response<Body>, AppError> {
         }
     }
 
-    return Ok(Response::new(Body::from(new_key)));
+    Ok(Response::new(Body::from(new_key)))
 }
 
 #[debug_handler]
@@ -453,76 +617,102 @@ async fn cleanup_logs(req: Request<Body>) -> Result<Response<Body>, AppError> {
     }
     let key = key.unwrap().to_str().unwrap();
 
-    let mut retention = Rentention::DELETE;
-    let retention_date;
-    let storage_path;
     {
         let config = CONFIG.lock().unwrap();
-
-        if key != config.get("master_key").unwrap() {
+        if key != config.master_key {
             return Err(AppError(anyhow::anyhow!(
                 "Invalid Master Authorization key"
             )));
         }
+    }
 
-        storage_path = config.get("storage_path").unwrap().to_owned();
+    let report = retention::run(Trigger::Manual).await;
+    let json_output = serde_json::to_string(&report)?;
+    let mut response = Response::new(Body::from(json_output));
+    if !report.errors.is_empty() {
+        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+    }
+    Ok(response)
+}
+
+#[debug_handler]
+async fn retention_status(req: Request<Body>) -> Result<Response<Body>, AppError> {
+    let key = req.headers().get("Authorization");
+    if key.is_none() {
+        return Err(AppError(anyhow::anyhow!(
+            "Authorization header is required"
+        )));
+    }
+    let key = key.unwrap().to_str().unwrap();
 
-        match config.get("mode").unwrap().as_str() {
-            "delete" => retention = Rentention::DELETE,
-            "archive" => retention = Rentention::ARCHIVE,
-            _ => retention = Rentention::DELETE,
+    {
+        let config = CONFIG.lock().unwrap();
+        if key != config.master_key {
+            return Err(AppError(anyhow::anyhow!(
+                "Invalid Master Authorization key"
+            )));
         }
+    }
 
-        let retention_days = config
-            .get("days_retained")
-            .unwrap()
-            .parse::<u32>()
-            .unwrap_or(14);
-        retention_date = chrono::Utc::now()
-            .checked_sub_signed(chrono::Duration::days(retention_days.into()))
-            .unwrap();
+    let json_output = serde_json::to_string(&retention::status())?;
+    Ok(Response::new(Body::from(json_output)))
+}
+
+#[derive(Deserialize)]
+struct RestoreRequest {
+    from: NaiveDate,
+    to: Option<NaiveDate>,
+}
+
+#[debug_handler]
+async fn restore_logs(req: Request<Body>) -> Result<Response<Body>, AppError> {
+    let key = req.headers().get("Authorization");
+    if key.is_none() {
+        return Err(AppError(anyhow::anyhow!(
+            "Authorization header is required"
+        )));
     }
+    let key = key.unwrap().to_str().unwrap();
 
-    let storage_path = Path::new(&storage_path);
-    let app_dirs = read_dir(storage_path)?;
-    for app_dir in app_dirs {
-        let app_dir = app_dir.unwrap();
-        let app_dir = app_dir.path();
-        let app = app_dir.file_name().unwrap().to_str().unwrap().to_string();
-        let app_path = storage_path.join(app);
+    let app = req.headers().get("Lumberjack-App");
+    if app.is_none() {
+        return Err(AppError(anyhow::anyhow!(
+            "Lumberjack-App header is required"
+        )));
+    }
+    let app = to_kebab_case(app.unwrap().to_str().unwrap());
 
-        let log_path = app_path.join("ledgers");
-        if !log_path.exists() {
-            continue;
-        }
+    let app_path: PathBuf;
+    let archive_path;
+    let archive_store;
+    {
+        let config = CONFIG.lock().unwrap();
 
-        let logs = read_dir(log_path)?;
-        for log in logs {
-            let log = log.unwrap();
-            let log = log.path();
-            let log_date = log.file_name().unwrap().to_str().unwrap().to_string();
-            let log_date = log_date.replace(".jsonl", "");
-            let log_date = chrono::DateTime::parse_from_rfc3339(log_date.as_str()).unwrap();
-            if log_date < retention_date {
-                match retention {
-                    Rentention::DELETE => {
-                        fs::remove_file(log)?;
-                        let search_cache = app_path
-                            .join("search")
-                            .join(log_date.format("%Y-%m-%d").to_string());
-                        if search_cache.exists() {
-                            fs::remove_dir_all(search_cache)?;
-                        }
-                    }
-                    Rentention::ARCHIVE => {
-                        todo!("Archive logs in S3 or similar storage");
-                    }
-                }
-            }
+        if key != config.master_key {
+            return Err(AppError(anyhow::anyhow!(
+                "Invalid Master Authorization key"
+            )));
         }
+
+        app_path = Path::new(&config.storage_path).join(&app);
+        archive_path = Path::new(&config.archive_path).to_owned();
+        archive_store = ArchiveStore::from_config(&config);
     }
 
-    return Ok(Response::new(Body::from("")));
+    let body = axum::body::to_bytes(req.into_body(), usize::MAX).await?;
+    let range: RestoreRequest = serde_json::from_slice(&body)?;
+
+    let restored = archive::restore_days(
+        &archive_store,
+        &app_path,
+        &archive_path,
+        range.from,
+        range.to.unwrap_or(range.from),
+    )
+    .await?;
+
+    let json_output = serde_json::to_string(&restored)?;
+    Ok(Response::new(Body::from(json_output)))
 }
 
 #[debug_handler]
@@ -543,28 +733,28 @@ async fn delete_key(req: Request<Body>) -> Result<Response<Body>, AppError> {
     }
     let app = to_kebab_case(app.unwrap().to_str().unwrap());
 
-    let mut app_path: PathBuf = Path::new("").to_path_buf();
+    let app_path: PathBuf;
     {
         let config = CONFIG.lock().unwrap();
 
-        if key != config.get("master_key").unwrap() {
+        if key != config.master_key {
             return Err(AppError(anyhow::anyhow!(
                 "Invalid Master Authorization key"
             )));
         }
 
-        app_path = Path::new(config.get("storage_path").unwrap()).join(&app);
+        app_path = Path::new(&config.storage_path).join(&app);
         if !app_path.exists() {
             fs::create_dir_all(&app_path)?;
         }
     }
 
-    app_path = app_path.join("keychain");
+    let app_path = app_path.join("keychain");
     if !app_path.exists() {
         return Ok(Response::new(Body::from("")));
     }
 
-    let body = axum::body::to_bytes(req.into_body(), std::usize::MAX).await?;
+    let body = axum::body::to_bytes(req.into_body(), usize::MAX).await?;
     if body.is_empty() {
         return Err(AppError(anyhow::anyhow!("Body is empty")));
     }
@@ -573,6 +763,7 @@ async fn delete_key(req: Request<Body>) -> Result<Response<Body>, AppError> {
 
     let app_keys_file = fs::OpenOptions::new()
         .create(true)
+        .truncate(false)
         .write(true)
         .read(true)
         .open(&app_path)?;
@@ -629,7 +820,7 @@ async fn delete_key(req: Request<Body>) -> Result<Response<Body>, AppError> {
         keychain.insert(app, app_keychain);
     }
 
-    return Ok(Response::new(Body::from("")));
+    Ok(Response::new(Body::from("")))
 }
 
 #[debug_handler]
@@ -652,7 +843,7 @@ async fn list_keys(req: Request<Body>) -> Result<Response<Body>, AppError> {
 
     {
         let config = CONFIG.lock().unwrap();
-        if key != config.get("master_key").unwrap() {
+        if key != config.master_key {
             return Err(AppError(anyhow::anyhow!(
                 "Invalid Master Authorization key"
             )));
@@ -666,10 +857,10 @@ async fn list_keys(req: Request<Body>) -> Result<Response<Body>, AppError> {
         return Ok(Response::new(Body::from(json_output)));
     }
 
-    return Err(AppError(anyhow::anyhow!(format!(
+    Err(AppError(anyhow::anyhow!(format!(
         "No applications exist with name {}",
         &app
-    ))));
+    ))))
 }
 
 #[debug_handler]
@@ -694,7 +885,7 @@ async fn stream_log(
         return Err(AppError(anyhow::anyhow!("File is required")));
     }
 
-    let mut app_path: PathBuf = Path::new("").to_path_buf();
+    let app_path: PathBuf;
     {
         let config = CONFIG.lock().unwrap();
         let keys = KEYS.lock().unwrap();
@@ -702,19 +893,13 @@ async fn stream_log(
         let mut authorized = false;
         if keys.contains_key(&app) {
             let app_keys = keys.get(&app).unwrap();
-            authorized = app_keys
-                .iter()
-                .filter_map(|k| {
-                    return Some(*k == key);
-                })
-                .collect::<Vec<bool>>()
-                .contains(&true);
+            authorized = app_keys.iter().any(|k| k == key);
         }
 
-        if !authorized && key != config.get("master_key").unwrap() {
+        if !authorized && key != config.master_key {
             return Err(AppError(anyhow::anyhow!("Invalid Authorization key")));
         }
-        app_path = Path::new(config.get("storage_path").unwrap()).join(app);
+        app_path = Path::new(&config.storage_path).join(app);
     }
 
     let log_path = app_path.join("ledgers").join(format!("{}.jsonl", file));
@@ -726,6 +911,105 @@ async fn stream_log(
     Ok(Response::new(Body::from(log)))
 }
 
+/// Streams an app's logs as NDJSON, filtered and a page at a time.
+#[debug_handler]
+async fn list_logs(
+    PathExtractor(app): PathExtractor<String>,
+    QueryExtractor(params): QueryExtractor<HashMap<String, String>>,
+    req: Request<Body>,
+) -> Result<Response<Body>, AppError> {
+    let key = req.headers().get("Authorization");
+    if key.is_none() {
+        return Err(AppError(anyhow::anyhow!(
+            "Authorization header is required"
+        )));
+    }
+    let key = key.unwrap().to_str().unwrap();
+    let app = app.to_lowercase().replace(".", "").replace("/", "");
+
+    if app.is_empty() {
+        return Err(AppError(anyhow::anyhow!("App is required")));
+    }
+    if !is_authorized(key, &app) {
+        return Err(AppError(anyhow::anyhow!("Invalid Authorization key")));
+    }
+
+    let date = |name: &str| -> Result<Option<NaiveDate>, Error> {
+        params
+            .get(name)
+            .map(|date| {
+                NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                    .map_err(|_| anyhow::anyhow!("Invalid {} date \"{}\"", name, date))
+            })
+            .transpose()
+    };
+    let from = date("from")?.ok_or_else(|| anyhow::anyhow!("from is required"))?;
+    let to = date("to")?.unwrap_or(from);
+    let limit = match params.get("limit") {
+        Some(limit) => limit
+            .parse::<usize>()
+            .map_err(|_| anyhow::anyhow!("Invalid limit \"{}\"", limit))?,
+        None => ledger::LIMIT,
+    }
+    .clamp(1, ledger::MAX_LIMIT);
+    let cursor = match params.get("cursor") {
+        Some(cursor) => {
+            Some(ledger::Cursor::decode(cursor).ok_or_else(|| anyhow::anyhow!("Invalid cursor"))?)
+        }
+        None => None,
+    };
+    let filter = ledger::Filter::from_params(&params, &["from", "to", "limit", "cursor"])?;
+
+    let app_path: PathBuf;
+    {
+        let config = CONFIG.lock().unwrap();
+        app_path = Path::new(&config.storage_path).join(app);
+    }
+
+    let body = ledger::stream(app_path, (from, to), filter, cursor, limit);
+    let mut response = Response::new(body);
+    response
+        .headers_mut()
+        .insert("Content-Type", "application/x-ndjson".parse()?);
+    Ok(response)
+}
+
+/// Follows the logs written to an app, over a WebSocket when the request asks
+/// for an upgrade and as Server-Sent Events otherwise.
+#[debug_handler]
+async fn tail_logs(
+    PathExtractor(app): PathExtractor<String>,
+    QueryExtractor(params): QueryExtractor<HashMap<String, String>>,
+    websocket: Option<WebSocketUpgrade>,
+    req: Request<Body>,
+) -> Result<Response<Body>, AppError> {
+    let key = req.headers().get("Authorization");
+    if key.is_none() {
+        return Err(AppError(anyhow::anyhow!(
+            "Authorization header is required"
+        )));
+    }
+    let key = key.unwrap().to_str().unwrap();
+    let app = app.to_lowercase().replace(".", "").replace("/", "");
+
+    if app.is_empty() {
+        return Err(AppError(anyhow::anyhow!("App is required")));
+    }
+    if !is_authorized(key, &app) {
+        return Err(AppError(anyhow::anyhow!("Invalid Authorization key")));
+    }
+    let filter = ledger::Filter::from_params(&params, &[])?;
+
+    // Subscribed before answering so no log is missed while the client connects.
+    let receiver = tail::subscribe();
+    match websocket {
+        Some(websocket) => {
+            Ok(websocket.on_upgrade(move |socket| tail::websocket(socket, receiver, app, filter)))
+        }
+        None => Ok(tail::sse(receiver, app, filter).into_response()),
+    }
+}
+
 #[debug_handler]
 async fn log_size(
     PathExtractor(params): PathExtractor<(String, String)>,
@@ -748,7 +1032,7 @@ async fn log_size(
         return Err(AppError(anyhow::anyhow!("File is required")));
     }
 
-    let mut app_path: PathBuf = Path::new("").to_path_buf();
+    let app_path: PathBuf;
     {
         let config = CONFIG.lock().unwrap();
         let keys = KEYS.lock().unwrap();
@@ -756,19 +1040,13 @@ async fn log_size(
         let mut authorized = false;
         if keys.contains_key(&app) {
             let app_keys = keys.get(&app).unwrap();
-            authorized = app_keys
-                .iter()
-                .filter_map(|k| {
-                    return Some(*k == key);
-                })
-                .collect::<Vec<bool>>()
-                .contains(&true);
+            authorized = app_keys.iter().any(|k| k == key);
         }
 
-        if !authorized && key != config.get("master_key").unwrap() {
+        if !authorized && key != config.master_key {
             return Err(AppError(anyhow::anyhow!("Invalid Authorization key")));
         }
-        app_path = Path::new(config.get("storage_path").unwrap()).join(app);
+        app_path = Path::new(&config.storage_path).join(app);
     }
 
     let log_path = app_path.join("ledgers").join(format!("{}.jsonl", file));
@@ -805,7 +1083,7 @@ async fn search_logs(
         return Err(AppError(anyhow::anyhow!("File is required")));
     }
 
-    let mut app_path: PathBuf = Path::new("").to_path_buf();
+    let app_path: PathBuf;
     {
         let config = CONFIG.lock().unwrap();
         let keys = KEYS.lock().unwrap();
@@ -813,58 +1091,145 @@ async fn search_logs(
         let mut authorized = false;
         if keys.contains_key(&app) {
             let app_keys = keys.get(&app).unwrap();
-            authorized = app_keys
-                .iter()
-                .filter_map(|k| {
-                    return Some(*k == key);
-                })
-                .collect::<Vec<bool>>()
-                .contains(&true);
+            authorized = app_keys.iter().any(|k| k == key);
         }
 
-        if !authorized && key != config.get("master_key").unwrap() {
+        if !authorized && key != config.master_key {
             return Err(AppError(anyhow::anyhow!("Invalid Authorization key")));
         }
-        app_path = Path::new(config.get("storage_path").unwrap()).join(app);
+        app_path = Path::new(&config.storage_path).join(app);
     }
 
-    let body = axum::body::to_bytes(req.into_body(), std::usize::MAX).await?;
+    let body = axum::body::to_bytes(req.into_body(), usize::MAX).await?;
     if body.is_empty() {
         return Err(AppError(anyhow::anyhow!("Body is empty")));
     }
     let query_string = String::from_utf8(body.to_vec())?;
 
-    let log_path = app_path.join("search").join(file);
+    let log_path = app_path.join("ledgers").join(format!("{}.jsonl", file));
     if !log_path.exists() {
         return Err(AppError(anyhow::anyhow!("Log file not found")));
     }
 
-    let output = Command::new("grep")
-        .arg("-r")
-        .arg("-l")
-        .arg("-P")
-        .arg("-i")
-        .arg(format!(".*{}.*", query_string.trim()))
-        .arg(log_path.to_str().unwrap())
-        .output()
-        .await?;
-    let error = String::from_utf8(output.stderr)?;
-    if !error.is_empty() {
-        return Err(AppError(anyhow::anyhow!("Failed to search logs")));
-    }
-    let filenames = String::from_utf8(output.stdout)?;
-    let filenames = filenames.split("\n").collect::<Vec<&str>>();
-    let filenames = filenames
+    let query = match query::parse(&query_string) {
+        Ok(query) => query,
+        Err(error) => return Ok(json_response(StatusCode::BAD_REQUEST, &error)?),
+    };
+    let logs = query::run(&app_path, &file, &query)?;
+    Ok(json_response(StatusCode::OK, &logs)?)
+}
+
+/// Page size of `/search` when the request doesn't set a limit.
+const SEARCH_LIMIT: usize = 100;
+const MAX_SEARCH_LIMIT: usize = 1000;
+
+#[derive(Deserialize)]
+struct SearchRequest {
+    query: Option<String>,
+    from: String,
+    to: Option<String>,
+    apps: Option<Vec<String>>,
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Searches across days and apps. The master key searches every app, other
+/// keys only the apps they belong to.
+#[debug_handler]
+async fn search_all_logs(req: Request<Body>) -> Result<Response<Body>, AppError> {
+    let key = req.headers().get("Authorization");
+    if key.is_none() {
+        return Err(AppError(anyhow::anyhow!(
+            "Authorization header is required"
+        )));
+    }
+    let key = key.unwrap().to_str().unwrap().to_string();
+
+    let body = axum::body::to_bytes(req.into_body(), usize::MAX).await?;
+    let request: SearchRequest = serde_json::from_slice(&body)?;
+
+    let from = query::parse_timestamp(&request.from)
+        .ok_or_else(|| anyhow::anyhow!("Invalid from timestamp \"{}\"", request.from))?;
+    let to = match &request.to {
+        Some(to) => query::parse_timestamp(to)
+            .ok_or_else(|| anyhow::anyhow!("Invalid to timestamp \"{}\"", to))?,
+        None => Utc::now(),
+    };
+    let cursor = match &request.cursor {
+        Some(cursor) => {
+            Some(query::Cursor::decode(cursor).ok_or_else(|| anyhow::anyhow!("Invalid cursor"))?)
+        }
+        None => None,
+    };
+    let limit = request
+        .limit
+        .unwrap_or(SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+    let query = match request.query.as_deref().map(str::trim) {
+        Some(query) if !query.is_empty() => match query::parse(query) {
+            Ok(query) => Some(query),
+            Err(error) => return Ok(json_response(StatusCode::BAD_REQUEST, &error)?),
+        },
+        _ => None,
+    };
+
+    let storage_path: PathBuf;
+    let master;
+    {
+        let config = CONFIG.lock().unwrap();
+        storage_path = Path::new(&config.storage_path).to_owned();
+        master = key == config.master_key;
+    }
+    let apps: Vec<String> = match request.apps {
+        Some(apps) => apps
+            .iter()
+            .map(|app| to_kebab_case(app).replace(".", "").replace("/", ""))
+            .collect(),
+        None if master => read_dir(&storage_path)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().join("ledgers").is_dir())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect(),
+        None => KEYS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, app_keys)| app_keys.contains(&key))
+            .map(|(app, _)| app.clone())
+            .collect(),
+    };
+    if !master && apps.is_empty() {
+        return Err(AppError(anyhow::anyhow!("Invalid Authorization key")));
+    }
+    if let Some(app) = apps.iter().find(|app| !is_authorized(&key, app)) {
+        return Err(AppError(anyhow::anyhow!(
+            "Invalid Authorization key for {}",
+            app
+        )));
+    }
+    if let Some(app) = apps
         .iter()
-        .filter(|&x| !x.is_empty())
-        .map(|x| x.rsplit_once('/').unwrap().1)
-        .collect::<Vec<&str>>();
-    let json_output = serde_json::to_string(&filenames)?;
-    Ok(Response::new(Body::from(json_output)))
+        .find(|app| app.is_empty() || !storage_path.join(app).join("ledgers").is_dir())
+    {
+        return Err(AppError(anyhow::anyhow!("Unknown app \"{}\"", app)));
+    }
+
+    let page = tokio::task::spawn_blocking(move || {
+        query::find(
+            &storage_path,
+            &apps,
+            (from, to),
+            query.as_ref(),
+            cursor.as_ref(),
+            limit,
+        )
+    })
+    .await??;
+    Ok(json_response(StatusCode::OK, &page)?)
 }
 
 #[debug_handler]
-async fn write_logs(req: Request<Body>) -> Result<StatusCode, AppError> {
+async fn write_logs(req: Request<Body>) -> Result<Response<Body>, AppError> {
     let key = req.headers().get("Authorization");
     if key.is_none() {
         return Err(AppError(anyhow::anyhow!(
@@ -883,7 +1248,11 @@ async fn write_logs(req: Request<Body>) -> Result<StatusCode, AppError> {
     let env = env.unwrap().to_str().unwrap().to_string();
     let app = to_kebab_case(app.unwrap().to_str().unwrap());
 
-    let mut app_path: PathBuf = Path::new("").to_path_buf();
+    let app_path: PathBuf;
+    let max_request_size;
+    let max_entry_size;
+    let idempotency_window;
+    let level_aliases;
     {
         let config = CONFIG.lock().unwrap();
         let keys = KEYS.lock().unwrap();
@@ -891,75 +1260,696 @@ async fn write_logs(req: Request<Body>) -> Result<StatusCode, AppError> {
         let mut authorized = false;
         if keys.contains_key(&app) {
             let app_keys = keys.get(&app).unwrap();
-            authorized = app_keys
-                .iter()
-                .filter_map(|k| {
-                    return Some(*k == key);
-                })
-                .collect::<Vec<bool>>()
-                .contains(&true);
+            authorized = app_keys.iter().any(|k| k == key);
         }
 
-        if !authorized && key != config.get("master_key").unwrap() {
+        if !authorized && key != config.master_key {
             return Err(AppError(anyhow::anyhow!("Invalid Authorization key")));
         }
 
-        app_path = Path::new(config.get("storage_path").unwrap()).join(app);
+        app_path = Path::new(&config.storage_path).join(&app);
         if !app_path.exists() {
             fs::create_dir_all(&app_path)?;
         }
+        max_request_size = config.max_request_size;
+        max_entry_size = config.max_entry_size;
+        idempotency_window = config.idempotency_window;
+        level_aliases = config.level_aliases.get(&app).cloned().unwrap_or_default();
     }
 
-    let body = axum::body::to_bytes(req.into_body(), std::usize::MAX).await?;
-    if body.is_empty() {
+    let content_length = req
+        .headers()
+        .get("Content-Length")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let mut report = IngestReport::default();
+    if content_length.is_some_and(|length| length > max_request_size) {
+        report.error = Some(format!(
+            "Body exceeds the maximum request size of {} bytes",
+            max_request_size
+        ));
+        return Ok(json_response(StatusCode::PAYLOAD_TOO_LARGE, &report)?);
+    }
+
+    // Entries are written as soon as they are complete, so a request rejected
+    // for its size still keeps everything received before the limit.
+    let content_type = req
+        .headers()
+        .get("Content-Type")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    let content_encoding = req
+        .headers()
+        .get("Content-Encoding")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    let idempotency_key = req
+        .headers()
+        .get("Idempotency-Key")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty());
+    let mut parser = BodyParser::new(&content_type, max_entry_size);
+    // The limit applies to the decompressed body, so a small compressed body
+    // can't expand past it.
+    let Some(mut body) = decode_body(&content_encoding, req.into_body()).await else {
+        report.error = Some(format!(
+            "Unsupported Content-Encoding \"{}\" (expected gzip, deflate, br or zstd)",
+            content_encoding
+        ));
+        return Ok(json_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, &report)?);
+    };
+
+    // A shipment retried with the same Idempotency-Key is read to check that
+    // it is the same body, then answered with the first response instead of
+    // being written again.
+    let mut idempotency = None;
+    let mut replay = None;
+    if let Some(key) = idempotency_key.filter(|_| idempotency_window > 0) {
+        let record_path = idempotency::record_path(&app_path, &key);
+        let Some(in_flight) = idempotency::begin(&record_path) else {
+            report.error =
+                Some("A request with this Idempotency-Key is still being processed".to_string());
+            return Ok(json_response(StatusCode::CONFLICT, &report)?);
+        };
+        replay = idempotency::lookup(&record_path, idempotency_window, Utc::now());
+        idempotency = Some((record_path, in_flight));
+    }
+
+    // With an Idempotency-Key the body is only written once it has been read
+    // in full, so a body cut short by the connection writes nothing and can be
+    // retried with the same key.
+    let mut pending = idempotency.as_ref().map(|_| Vec::new());
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut received: u64 = 0;
+    let status = loop {
+        let read = match body.read(&mut buffer).await {
+            Ok(0) => break StatusCode::OK,
+            Ok(read) => read,
+            Err(error) => {
+                report.error = Some(format!("Failed to read the body: {}", error));
+                return Ok(json_response(StatusCode::BAD_REQUEST, &report)?);
+            }
+        };
+        received += read as u64;
+        if received > max_request_size {
+            report.error = Some(format!(
+                "Body exceeds the maximum request size of {} bytes",
+                max_request_size
+            ));
+            break StatusCode::PAYLOAD_TOO_LARGE;
+        }
+
+        hasher.update(&buffer[..read]);
+        if let Some(pending) = &mut pending {
+            pending.extend_from_slice(&buffer[..read]);
+        } else {
+            let entries = parser.feed(&buffer[..read]);
+            write_entries(
+                &parser,
+                entries,
+                &env,
+                &level_aliases,
+                &app_path,
+                &mut report,
+            )?;
+        }
+    };
+    if received == 0 {
         return Err(AppError(anyhow::anyhow!("Body is empty")));
     }
+    let body_hash = hex::encode(hasher.finalize());
 
-    let mut logs: Vec<Log> = Vec::new();
-    let string_body = String::from_utf8(body.to_vec())?;
-    let mut building_log = false;
-    let mut lines: Vec<String> = Vec::new();
-    for line in string_body.lines() {
-        if building_log {
-            match line {
-                "---[EOL]---" => {
-                    building_log = false;
-                    let mut new_log = create_log(&lines);
-                    new_log.env = env.clone();
-                    logs.push(new_log);
-                    lines.clear();
-                }
-                _ => {
-                    lines.push(line.to_string());
+    if let Some(record) = replay {
+        if status != StatusCode::OK {
+            return Ok(json_response(status, &report)?);
+        }
+        if record.body_hash != body_hash {
+            report.error =
+                Some("Idempotency-Key was already used for a different body".to_string());
+            return Ok(json_response(StatusCode::UNPROCESSABLE_ENTITY, &report)?);
+        }
+        let mut response = Response::new(Body::from(record.response));
+        *response.status_mut() = StatusCode::from_u16(record.status)?;
+        response
+            .headers_mut()
+            .insert("Content-Type", "application/json".parse()?);
+        response
+            .headers_mut()
+            .insert("Idempotency-Replayed", "true".parse()?);
+        return Ok(response);
+    }
+
+    let mut write = || -> Result<(), Error> {
+        let mut entries = match pending.take() {
+            Some(pending) => parser.feed(&pending),
+            None => Vec::new(),
+        };
+        if status == StatusCode::OK {
+            entries.extend(parser.finish());
+        }
+        write_entries(
+            &parser,
+            entries,
+            &env,
+            &level_aliases,
+            &app_path,
+            &mut report,
+        )
+    };
+    let status = match write() {
+        Ok(()) => status,
+        // Some entries may already be written, so the failure is recorded to
+        // keep a retry from writing them again.
+        Err(error) if idempotency.is_some() => {
+            report.error = Some(format!("Failed to write logs: {:#}", error));
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+        Err(error) => return Err(AppError(error)),
+    };
+    if let Some((record_path, _in_flight)) = idempotency {
+        let record = idempotency::Record {
+            created: Utc::now(),
+            body_hash,
+            status: status.as_u16(),
+            response: serde_json::to_string(&report)?,
+        };
+        // The logs are already written, so failing to remember the key only
+        // means a retry would be written again.
+        if let Err(error) = idempotency::save(&record_path, &record) {
+            eprintln!("Failed to save idempotency record: {:#}", error);
+        }
+    }
+
+    Ok(json_response(status, &report)?)
+}
+
+/// Parses a body exactly like `POST /logs` without writing anything, so log
+/// formatters can be tested against the parser.
+#[debug_handler]
+async fn validate_logs(req: Request<Body>) -> Result<Response<Body>, AppError> {
+    let key = req.headers().get("Authorization");
+    if key.is_none() {
+        return Err(AppError(anyhow::anyhow!(
+            "Authorization header is required"
+        )));
+    }
+    let key = key.unwrap().to_str().unwrap().to_string();
+
+    let app = req.headers().get("Lumberjack-App");
+    if app.is_none() {
+        return Err(AppError(anyhow::anyhow!(
+            "Lumberjack-App header is required"
+        )));
+    }
+    let app = to_kebab_case(app.unwrap().to_str().unwrap());
+    let env = req
+        .headers()
+        .get("Lumberjack-Env")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    if !is_authorized(&key, &app) {
+        return Err(AppError(anyhow::anyhow!("Invalid Authorization key")));
+    }
+
+    let max_request_size;
+    let max_entry_size;
+    let level_aliases;
+    {
+        let config = CONFIG.lock().unwrap();
+        max_request_size = config.max_request_size;
+        max_entry_size = config.max_entry_size;
+        level_aliases = config.level_aliases.get(&app).cloned().unwrap_or_default();
+    }
+
+    let content_type = req
+        .headers()
+        .get("Content-Type")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    let content_encoding = req
+        .headers()
+        .get("Content-Encoding")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    let mut report = ValidationReport::default();
+    let mut parser = BodyParser::new(&content_type, max_entry_size);
+    let Some(mut body) = decode_body(&content_encoding, req.into_body()).await else {
+        report.error = Some(format!(
+            "Unsupported Content-Encoding \"{}\" (expected gzip, deflate, br or zstd)",
+            content_encoding
+        ));
+        return Ok(json_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, &report)?);
+    };
+
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut received: u64 = 0;
+    loop {
+        let read = match body.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(error) => {
+                report.error = Some(format!("Failed to read the body: {}", error));
+                return Ok(json_response(StatusCode::BAD_REQUEST, &report)?);
+            }
+        };
+        received += read as u64;
+        if received > max_request_size {
+            report.error = Some(format!(
+                "Body exceeds the maximum request size of {} bytes",
+                max_request_size
+            ));
+            return Ok(json_response(StatusCode::PAYLOAD_TOO_LARGE, &report)?);
+        }
+        let entries = parser.feed(&buffer[..read]);
+        validate_entries(&parser, entries, &env, &level_aliases, &mut report);
+    }
+    if received == 0 {
+        return Err(AppError(anyhow::anyhow!("Body is empty")));
+    }
+    let entries = parser.finish();
+    validate_entries(&parser, entries, &env, &level_aliases, &mut report);
+
+    Ok(json_response(StatusCode::OK, &report)?)
+}
+
+/// Builds the logs of a batch of parsed entries for a validation report,
+/// along with the warnings about every entry.
+fn validate_entries(
+    parser: &BodyParser,
+    entries: Vec<Result<Entry, RejectedEntry>>,
+    env: &str,
+    level_aliases: &HashMap<String, ErrorLevel>,
+    report: &mut ValidationReport,
+) {
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(rejected) => {
+                report.rejected.push(rejected);
+                continue;
+            }
+        };
+        match parser.create_log(&entry, level_aliases) {
+            Ok(mut log) => {
+                log.env = env.to_string();
+                report.warnings.extend(parser.warnings(&entry, Some(&log)));
+                report.logs.push(ValidatedLog {
+                    index: entry.index,
+                    log,
+                });
+            }
+            Err(reason) => {
+                report.warnings.extend(parser.warnings(&entry, None));
+                report
+                    .rejected
+                    .push(RejectedEntry::new(entry.index, &entry.lines[0], reason));
+            }
+        }
+    }
+}
+
+/// Wraps a request body in a decoder for its `Content-Encoding`, or returns
+/// `None` when the encoding isn't supported.
+async fn decode_body(content_encoding: &str, body: Body) -> Option<Pin<Box<dyn AsyncRead + Send>>> {
+    let mut body = StreamReader::new(
+        body.into_data_stream()
+            .map(|chunk| chunk.map_err(std::io::Error::other)),
+    );
+    let body: Pin<Box<dyn AsyncRead + Send>> = match content_encoding.trim().to_lowercase().as_str()
+    {
+        "" | "identity" => Box::pin(body),
+        "gzip" | "x-gzip" => Box::pin(GzipDecoder::new(body)),
+        // Some clients send raw deflate data rather than the zlib stream
+        // HTTP asks for, which is told apart by the zlib header.
+        "deflate" => {
+            let zlib = match body.fill_buf().await {
+                Ok([first, second, ..]) => {
+                    *first & 0x0f == 8 && (*first as u16 * 256 + *second as u16).is_multiple_of(31)
                 }
+                _ => true,
+            };
+            match zlib {
+                true => Box::pin(ZlibDecoder::new(body)),
+                false => Box::pin(DeflateDecoder::new(body)),
+            }
+        }
+        "br" => Box::pin(BrotliDecoder::new(body)),
+        "zstd" => Box::pin(ZstdDecoder::new(body)),
+        _ => return None,
+    };
+    Some(body)
+}
+
+/// Builds the logs of a batch of parsed entries and writes the valid ones,
+/// recording every accepted and rejected entry in the report.
+fn write_entries(
+    parser: &BodyParser,
+    entries: Vec<Result<Entry, RejectedEntry>>,
+    env: &str,
+    level_aliases: &HashMap<String, ErrorLevel>,
+    app_path: &Path,
+    report: &mut IngestReport,
+) -> Result<(), Error> {
+    let mut logs: Vec<Log> = Vec::new();
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(rejected) => {
+                report.rejected.push(rejected);
+                continue;
+            }
+        };
+        match parser.create_log(&entry, level_aliases) {
+            Ok(mut new_log) => {
+                new_log.env = env.to_string();
+                logs.push(new_log);
+            }
+            Err(reason) => {
+                report
+                    .rejected
+                    .push(RejectedEntry::new(entry.index, &entry.lines[0], reason))
             }
-        } else {
-            building_log = true;
-            lines.push(line.to_string());
         }
     }
 
-    write_log_files(logs, app_path)?;
+    let accepted = logs.len();
+    write_log_files(logs, app_path.to_path_buf())?;
+    report.accepted += accepted;
+    Ok(())
+}
 
-    Ok(StatusCode::OK)
+fn json_response(status: StatusCode, body: &impl Serialize) -> Result<Response<Body>, Error> {
+    let json_output = serde_json::to_string(body)?;
+    let mut response = Response::new(Body::from(json_output));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert("Content-Type", "application/json".parse()?);
+    Ok(response)
+}
+
+/// Accepts an OTLP/HTTP logs export. Each resource is written to the app named
+/// by its `service.name`, and records the key cannot write are reported back
+/// as a partial success.
+#[debug_handler]
+async fn write_otlp_logs(req: Request<Body>) -> Result<Response<Body>, AppError> {
+    let key = req.headers().get("Authorization");
+    if key.is_none() {
+        return Err(AppError(anyhow::anyhow!(
+            "Authorization header is required"
+        )));
+    }
+    let key = key.unwrap().to_str().unwrap().to_string();
+    let header_env = req
+        .headers()
+        .get("Lumberjack-Env")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+
+    let content_type = req
+        .headers()
+        .get("Content-Type")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    let Some(encoding) = otlp::Encoding::from_content_type(content_type) else {
+        return Ok((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Content-Type must be application/x-protobuf or application/json",
+        )
+            .into_response());
+    };
+
+    let storage_path: PathBuf;
+    let max_request_size;
+    {
+        let config = CONFIG.lock().unwrap();
+        storage_path = Path::new(&config.storage_path).to_owned();
+        max_request_size = config.max_request_size;
+    }
+
+    let Ok(body) = axum::body::to_bytes(req.into_body(), max_request_size as usize).await else {
+        return Ok((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "Body exceeds the maximum request size of {} bytes",
+                max_request_size
+            ),
+        )
+            .into_response());
+    };
+    let request = match otlp::decode(encoding, &body) {
+        Ok(request) => request,
+        Err(error) => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                format!("Invalid OTLP payload: {:#}", error),
+            )
+                .into_response())
+        }
+    };
+
+    let mut rejected = 0;
+    let mut errors: Vec<String> = Vec::new();
+    for batch in otlp::resource_batches(request) {
+        let app = batch
+            .service_name
+            .map(|name| to_kebab_case(&name))
+            .filter(|app| !app.is_empty());
+        let Some(app) = app else {
+            rejected += batch.logs.len();
+            errors.push("resource has no service.name attribute".to_string());
+            continue;
+        };
+        if !is_authorized(&key, &app) {
+            rejected += batch.logs.len();
+            errors.push(format!("Invalid Authorization key for {}", app));
+            continue;
+        }
+
+        let env = batch
+            .environment
+            .or(header_env.clone())
+            .unwrap_or_else(|| "otlp".to_string());
+        let mut logs = batch.logs;
+        for log in logs.iter_mut() {
+            log.env = env.clone();
+        }
+        write_log_files(logs, storage_path.join(&app))?;
+    }
+    errors.dedup();
+
+    let mut response = Response::new(Body::from(otlp::encode_response(
+        encoding,
+        rejected,
+        &errors.join("; "),
+    )));
+    response
+        .headers_mut()
+        .insert("Content-Type", encoding.content_type().parse()?);
+    Ok(response)
+}
+
+/// Accepts a Loki push so Promtail and Grafana Agent can ship to lumberjack.
+/// Every stream must name an app the key can write to, otherwise nothing in
+/// the request is written.
+#[debug_handler]
+async fn write_loki_logs(req: Request<Body>) -> Result<Response<Body>, AppError> {
+    let key = req.headers().get("Authorization");
+    if key.is_none() {
+        return Ok((StatusCode::UNAUTHORIZED, "Authorization header is required").into_response());
+    }
+    let key = loki::api_key(key.unwrap().to_str().unwrap());
+    let header_env = req
+        .headers()
+        .get("Lumberjack-Env")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+    let content_type = req
+        .headers()
+        .get("Content-Type")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+
+    let storage_path: PathBuf;
+    let max_request_size;
+    {
+        let config = CONFIG.lock().unwrap();
+        storage_path = Path::new(&config.storage_path).to_owned();
+        max_request_size = config.max_request_size;
+    }
+
+    let Ok(body) = axum::body::to_bytes(req.into_body(), max_request_size as usize).await else {
+        return Ok((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "Body exceeds the maximum request size of {} bytes",
+                max_request_size
+            ),
+        )
+            .into_response());
+    };
+    let streams = match loki::decode(&content_type, &body) {
+        Ok(streams) => streams,
+        Err(error) => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                format!("Invalid push request: {:#}", error),
+            )
+                .into_response())
+        }
+    };
+
+    // Loki clients drop batches rejected with a 4xx instead of retrying them,
+    // so every stream is checked before anything is written.
+    let mut batches: Vec<(String, loki::StreamBatch)> = Vec::new();
+    for stream in streams {
+        let batch = loki::stream_batch(stream);
+        let app = batch
+            .app
+            .as_deref()
+            .map(to_kebab_case)
+            .filter(|app| !app.is_empty());
+        let Some(app) = app else {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                "Every stream needs an app, service_name or job label",
+            )
+                .into_response());
+        };
+        if !is_authorized(&key, &app) {
+            return Ok((
+                StatusCode::UNAUTHORIZED,
+                format!("Invalid Authorization key for {}", app),
+            )
+                .into_response());
+        }
+        batches.push((app, batch));
+    }
+
+    for (app, batch) in batches {
+        let env = batch
+            .env
+            .or(header_env.clone())
+            .unwrap_or_else(|| "loki".to_string());
+        let mut logs = batch.logs;
+        for log in logs.iter_mut() {
+            log.env = env.clone();
+        }
+        write_log_files(logs, storage_path.join(&app))?;
+    }
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Accepts a single GELF message, optionally gzip or zlib compressed. The app
+/// comes from the `Lumberjack-App` header or the message's `_app` field.
+#[debug_handler]
+async fn write_gelf_log(req: Request<Body>) -> Result<Response<Body>, AppError> {
+    let key = req.headers().get("Authorization");
+    if key.is_none() {
+        return Err(AppError(anyhow::anyhow!(
+            "Authorization header is required"
+        )));
+    }
+    let key = key.unwrap().to_str().unwrap().to_string();
+    let header_app = req
+        .headers()
+        .get("Lumberjack-App")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+    let env = req
+        .headers()
+        .get("Lumberjack-Env")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("gelf")
+        .to_string();
+
+    let storage_path: PathBuf;
+    let max_request_size;
+    let max_entry_size;
+    {
+        let config = CONFIG.lock().unwrap();
+        storage_path = Path::new(&config.storage_path).to_owned();
+        max_request_size = config.max_request_size;
+        max_entry_size = config.max_entry_size;
+    }
+
+    let Ok(body) = axum::body::to_bytes(req.into_body(), max_request_size as usize).await else {
+        return Ok((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "Body exceeds the maximum request size of {} bytes",
+                max_request_size
+            ),
+        )
+            .into_response());
+    };
+    let parsed = gelf::decompress(&body, max_entry_size).and_then(|message| gelf::parse(&message));
+    let (message_app, mut log) = match parsed {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                format!("Invalid GELF message: {:#}", error),
+            )
+                .into_response())
+        }
+    };
+
+    let app = header_app
+        .or(message_app)
+        .map(|app| to_kebab_case(&app))
+        .filter(|app| !app.is_empty());
+    let Some(app) = app else {
+        return Err(AppError(anyhow::anyhow!(
+            "Lumberjack-App header or _app field is required"
+        )));
+    };
+    if !is_authorized(&key, &app) {
+        return Err(AppError(anyhow::anyhow!("Invalid Authorization key")));
+    }
+
+    log.env = env;
+    write_log_files(vec![log], storage_path.join(&app))?;
+    Ok(StatusCode::ACCEPTED.into_response())
+}
+
+/// Whether the key is the master key or one of the app's keys.
+fn is_authorized(key: &str, app: &str) -> bool {
+    let config = CONFIG.lock().unwrap();
+    let keys = KEYS.lock().unwrap();
+    key == config.master_key
+        || keys
+            .get(app)
+            .is_some_and(|app_keys| app_keys.iter().any(|k| k == key))
 }
 
 fn write_log_files(logs: Vec<Log>, app_path: PathBuf) -> Result<(), Error> {
+    let app = app_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
     let daily_ledger_path = app_path.clone().join("ledgers");
     if !daily_ledger_path.exists() {
         fs::create_dir_all(&daily_ledger_path)?;
     }
 
-    for log in logs {
+    let ledger_lock = LEDGER_LOCK.lock().unwrap();
+    let mut days: HashMap<String, Vec<Log>> = HashMap::new();
+    for log in logs.iter() {
         let log_date = DateTime::parse_from_rfc3339(log.timestamp.as_str())?
             .format("%Y-%m-%d")
             .to_string();
 
-        let daily_log_path = app_path.clone().join("search").join(log_date.clone());
-        if !daily_log_path.exists() {
-            fs::create_dir_all(&daily_log_path)?;
-        }
-
         let ledger = daily_ledger_path.join(format!("{}.jsonl", log_date));
         let mut ledger = fs::OpenOptions::new()
             .create(true)
@@ -970,129 +1960,85 @@ fn write_log_files(logs: Vec<Log>, app_path: PathBuf) -> Result<(), Error> {
         ledger.write_all(log_json.as_bytes())?;
         ledger.write_all("\n".as_bytes())?;
 
-        let message_cache = daily_log_path.join(format!("{}", log.uid));
-        let mut message_cache = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(&message_cache)?;
-        message_cache.write_all(log.message.as_bytes())?;
+        days.entry(log_date).or_default().push(log.clone());
+    }
+    for (date, logs) in days {
+        search::queue(&app_path, &date, logs)?;
+    }
+    // Subscribers and webhooks don't need the ledgers, so other writes can go ahead.
+    drop(ledger_lock);
+    for log in logs.iter() {
+        tail::publish(&app, log);
+        notify::dispatch(&app, log);
     }
 
     Ok(())
 }
 
-fn create_log(lines: &Vec<String>) -> Log {
-    let mut lines = lines.iter().peekable();
-    let mut new_log: Log = Log {
-        uid: Uuid::now_v7().to_string(),
-        level: ErrorLevel::Unknown,
-        file: "".to_string(),
-        function: "".to_string(),
-        line: None,
-        timestamp: "".to_string(),
-        message: "".to_string(),
-        custom: HashMap::new(),
-        branch: "".to_string(),
-        env: "".to_string(),
-        category: "".to_string(),
-    };
-
-    let first_line = lines.next().unwrap();
-    let (level, timestamp) = first_line.split_once("-").unwrap();
-    match level.to_uppercase().trim() {
-        "[EMERGENCY]" => new_log.level = ErrorLevel::Emergency,
-        "[ALERT]" => new_log.level = ErrorLevel::Alert,
-        "[CRITICAL]" => new_log.level = ErrorLevel::Critical,
-        "[ERROR]" => new_log.level = ErrorLevel::Error,
-        "[WARNING]" => new_log.level = ErrorLevel::Warning,
-        "[NOTICE]" => new_log.level = ErrorLevel::Notice,
-        "[INFORMATIONAL]" => new_log.level = ErrorLevel::Info,
-        "[DEBUG]" => new_log.level = ErrorLevel::Debug,
-        _ => new_log.level = ErrorLevel::Unknown,
-    }
-    new_log.timestamp = timestamp.trim().to_string();
-
-    loop {
-        let binding = String::from("---[EOL]---");
-        let line = lines.next().unwrap_or(&binding);
-
-        let mut result = String::new();
-        let mut section = LogSection::Message;
-        let mut line_parts = line.split(":");
-        match line_parts
-            .nth(0)
-            .unwrap_or("")
-            .trim()
-            .to_uppercase()
-            .as_str()
-        {
-            "MESSAGE" => {
-                section = LogSection::Message;
-                result = parse_log_message(&mut lines);
-            }
-            "FILE" => {
-                section = LogSection::File;
-                result = line_parts.collect();
-            }
-            "FUNCTION" => {
-                section = LogSection::Function;
-                result = line_parts.collect();
-            }
-            "LINE" => {
-                section = LogSection::Line;
-                result = line_parts.collect();
-            }
-            "CATEGORY" => {
-                section = LogSection::Category;
-                result = line_parts.collect();
-            }
-            "BRANCH" => {
-                section = LogSection::Branch;
-                result = line_parts.collect();
-            }
-            "---[EOL]---" => break,
-            _ => {
-                section = LogSection::Custom;
-                result = line.clone();
-            }
-        }
-        match section {
-            LogSection::Message => new_log.message = result.trim().to_string(),
-            LogSection::File => new_log.file = result.trim().to_string(),
-            LogSection::Function => new_log.function = result.trim().to_string(),
-            LogSection::Line => new_log.line = Some(result.trim().parse().unwrap_or(0)),
-            LogSection::Category => new_log.category = result.trim().to_string(),
-            LogSection::Branch => new_log.branch = result.trim().to_string(),
-            LogSection::Custom => {
-                let mut parts = result.split(":");
-                let key = parts.next().unwrap_or("").trim().to_string();
-                let value = parts.next().unwrap_or("").trim().to_string();
-                new_log.custom.insert(key, value);
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &str = "[Error] - 2024-04-06T08:48:24Z\nMessage:\nfirst\n---[EOL]---\n\
+                        [Error] - 2024-04-06T08:48:25Z\nMessage:\nsecond\n---[EOL]---\n";
+
+    fn request(body: Body) -> Request<Body> {
+        Request::builder()
+            .method("POST")
+            .uri("/logs")
+            .header("Authorization", "master")
+            .header("Lumberjack-App", "idempotent")
+            .header("Lumberjack-Env", "test")
+            .header("Idempotency-Key", "shipment")
+            .body(body)
+            .unwrap()
     }
 
-    return new_log;
-}
-
-fn parse_log_message(lines: &mut Peekable<Iter<'_, String>>) -> String {
-    let mut result = String::new();
-
-    loop {
-        match lines
-            .peek()
-            .unwrap_or(&&String::from("---[EOL]---"))
-            .trim()
-            .to_uppercase()
-            .as_str()
-        {
-            "---[EOL]---" => break,
-            _ => {
-                let line = lines.next().unwrap();
-                result = result + "\n" + line.trim();
-            }
+    async fn send(request: Request<Body>) -> (StatusCode, String) {
+        let Ok(response) = write_logs(request).await else {
+            panic!("request failed");
         };
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
     }
 
-    return result.trim().to_string();
+    #[tokio::test]
+    async fn writes_nothing_when_an_idempotent_body_is_cut_short() {
+        let storage_path =
+            std::env::temp_dir().join(format!("lumberjack-main-{}", uuid::Uuid::now_v7()));
+        {
+            let mut config = CONFIG.lock().unwrap();
+            config.storage_path = storage_path.to_string_lossy().to_string();
+            config.master_key = "master".to_string();
+        }
+        let ledger = storage_path
+            .join("idempotent")
+            .join("ledgers")
+            .join("2024-04-06.jsonl");
+
+        // Both entries arrive before the connection drops.
+        let chunks: Vec<std::io::Result<&str>> =
+            vec![Ok(BODY), Err(std::io::Error::other("connection reset"))];
+        let (status, _) = send(request(Body::from_stream(futures_util::stream::iter(
+            chunks,
+        ))))
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(!ledger.exists());
+
+        let (status, response) = send(request(Body::from(BODY))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response, r#"{"accepted":2,"rejected":[]}"#);
+        let (status, replayed) = send(request(Body::from(BODY))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(replayed, response);
+        assert_eq!(fs::read_to_string(&ledger).unwrap().lines().count(), 2);
+
+        // Waits for the indexer to be done with the day before removing it.
+        search::reconcile(&storage_path.join("idempotent"), "2024-04-06").unwrap();
+        fs::remove_dir_all(&storage_path).unwrap();
+    }
 }
@@ -0,0 +1,398 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use reqwest::header::RETRY_AFTER;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::config::{Config, WebhookConfig};
+use crate::{ErrorLevel, Log};
+
+/// Notifications waiting to be delivered to a webhook before new ones are
+/// dropped.
+const QUEUE_SIZE: usize = 1024;
+const MAX_ATTEMPTS: u32 = 4;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Longest `Retry-After` a rate limited webhook gets to ask for.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+static QUEUES: OnceLock<Vec<Queue>> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Provider {
+    Discord,
+    Slack,
+    Teams,
+}
+
+struct Notification {
+    app: String,
+    log: Log,
+}
+
+/// Every webhook has its own queue and a worker that delivers one notification
+/// at a time, so a burst of logs waits its turn or is dropped instead of
+/// flooding the provider.
+struct Queue {
+    provider: Provider,
+    webhook: WebhookConfig,
+    sender: mpsc::Sender<Notification>,
+}
+
+/// Starts the background tasks that deliver notifications. Does nothing when no
+/// webhook is configured.
+pub fn start(config: &Config) {
+    let webhooks: Vec<(Provider, WebhookConfig)> = vec![
+        (Provider::Discord, config.discord.clone()),
+        (Provider::Slack, config.slack.clone()),
+        (Provider::Teams, config.teams.clone()),
+    ]
+    .into_iter()
+    .filter(|(_, webhook)| !webhook.url.is_empty() && !webhook.levels.is_empty())
+    .collect();
+
+    if webhooks.is_empty() {
+        return;
+    }
+
+    let mut queues = Vec::new();
+    let mut workers = Vec::new();
+    for (provider, webhook) in webhooks {
+        let (sender, receiver) = mpsc::channel::<Notification>(QUEUE_SIZE);
+        workers.push((provider, webhook.url.clone(), receiver));
+        queues.push(Queue {
+            provider,
+            webhook,
+            sender,
+        });
+    }
+    if QUEUES.set(queues).is_err() {
+        return;
+    }
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("Failed to build notification HTTP client");
+    for (provider, url, mut receiver) in workers {
+        let client = client.clone();
+        tokio::spawn(async move {
+            while let Some(notification) = receiver.recv().await {
+                let payload = payload(provider, &notification.app, &notification.log);
+                if let Err(error) =
+                    deliver(&client, &url, &payload, MAX_ATTEMPTS, RETRY_DELAY).await
+                {
+                    eprintln!("Failed to send {:?} notification: {:#}", provider, error);
+                }
+            }
+        });
+    }
+}
+
+/// Queues a log for delivery to every webhook subscribed to its level. Never
+/// blocks the caller; when a webhook's queue is full the notification is
+/// dropped for that webhook.
+pub fn dispatch(app: &str, log: &Log) {
+    let Some(queues) = QUEUES.get() else {
+        return;
+    };
+    for queue in queues {
+        if !queue.webhook.sends(log.level) {
+            continue;
+        }
+        let notification = Notification {
+            app: app.to_string(),
+            log: log.clone(),
+        };
+        if let Err(mpsc::error::TrySendError::Full(_)) = queue.sender.try_send(notification) {
+            eprintln!(
+                "{:?} notification queue is full, dropping notification for {}",
+                queue.provider, app
+            );
+        }
+    }
+}
+
+/// POSTs the payload, retrying with exponential backoff on connection errors,
+/// rate limiting and server errors. A rate limited retry waits as long as the
+/// webhook's `Retry-After` asks, when it sets one.
+pub async fn deliver(
+    client: &reqwest::Client,
+    url: &str,
+    payload: &Value,
+    max_attempts: u32,
+    retry_delay: Duration,
+) -> Result<()> {
+    let mut attempt = 1;
+    loop {
+        let mut delay = retry_delay * 2_u32.pow(attempt - 1);
+        let error = match client.post(url).json(payload).send().await {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => {
+                let status = response.status();
+                if status == StatusCode::TOO_MANY_REQUESTS {
+                    if let Some(retry_after) = retry_after(&response) {
+                        delay = retry_after.min(MAX_RETRY_AFTER);
+                    }
+                } else if !status.is_server_error() {
+                    return Err(anyhow!("webhook responded with {}", status));
+                }
+                anyhow!("webhook responded with {}", status)
+            }
+            Err(error) => error.into(),
+        };
+
+        if attempt >= max_attempts {
+            return Err(error.context(format!("gave up after {} attempts", attempt)));
+        }
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Reads `Retry-After` as either seconds or an HTTP date.
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Duration::try_from_secs_f64(seconds).ok();
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+pub fn payload(provider: Provider, app: &str, log: &Log) -> Value {
+    let title = format!("[{}] {} ({})", log.level, app, log.env);
+    match provider {
+        Provider::Discord => {
+            let fields: Vec<Value> = fields(log)
+                .into_iter()
+                .map(|(name, value)| json!({ "name": name, "value": truncate(&value, 1024), "inline": true }))
+                .collect();
+            json!({
+                "embeds": [{
+                    "title": truncate(&title, 256),
+                    "description": truncate(&log.message, 4096),
+                    "color": color(log.level),
+                    "timestamp": log.timestamp,
+                    "fields": fields,
+                }]
+            })
+        }
+        Provider::Slack => {
+            let mut blocks = vec![
+                json!({
+                    "type": "header",
+                    "text": { "type": "plain_text", "text": truncate(&title, 150) },
+                }),
+                json!({
+                    "type": "section",
+                    "text": { "type": "mrkdwn", "text": format!("```{}```", truncate(&log.message, 2900)) },
+                }),
+            ];
+            let context: Vec<Value> = fields(log)
+                .into_iter()
+                .map(|(name, value)| json!({ "type": "mrkdwn", "text": format!("*{}:* {}", name, value) }))
+                .take(10)
+                .collect();
+            if !context.is_empty() {
+                blocks.push(json!({ "type": "context", "elements": context }));
+            }
+            json!({ "text": title, "blocks": blocks })
+        }
+        Provider::Teams => {
+            let facts: Vec<Value> = fields(log)
+                .into_iter()
+                .map(|(name, value)| json!({ "name": name, "value": value }))
+                .collect();
+            json!({
+                "@type": "MessageCard",
+                "@context": "http://schema.org/extensions",
+                "themeColor": format!("{:06X}", color(log.level)),
+                "summary": title,
+                "title": title,
+                "sections": [{
+                    "facts": facts,
+                    "text": truncate(&log.message, 10000),
+                }]
+            })
+        }
+    }
+}
+
+/// The non-empty metadata shown alongside the message.
+fn fields(log: &Log) -> Vec<(&'static str, String)> {
+    let line = log.line.map(|line| line.to_string()).unwrap_or_default();
+    vec![
+        ("Timestamp", log.timestamp.clone()),
+        ("Category", log.category.clone()),
+        ("Branch", log.branch.clone()),
+        ("File", log.file.clone()),
+        ("Function", log.function.clone()),
+        ("Line", line),
+    ]
+    .into_iter()
+    .filter(|(_, value)| !value.is_empty())
+    .collect()
+}
+
+fn color(level: ErrorLevel) -> u32 {
+    match level {
+        ErrorLevel::Emergency | ErrorLevel::Alert | ErrorLevel::Critical => 0x8E0000,
+        ErrorLevel::Error => 0xE53935,
+        ErrorLevel::Warning => 0xFB8C00,
+        ErrorLevel::Notice => 0xFDD835,
+        ErrorLevel::Info => 0x1E88E5,
+        ErrorLevel::Debug | ErrorLevel::Unknown => 0x757575,
+    }
+}
+
+fn truncate(value: &str, max_chars: usize) -> String {
+    if value.chars().count() <= max_chars {
+        return value.to_string();
+    }
+    let mut truncated: String = value.chars().take(max_chars - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, http::StatusCode, routing::post, Json, Router};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    fn test_log() -> Log {
+        Log {
+            uid: "0".to_string(),
+            level: ErrorLevel::Error,
+            file: "/srv/app/DatabaseService.php".to_string(),
+            function: "query()".to_string(),
+            line: Some(69),
+            timestamp: "2024-04-06T08:48:24Z".to_string(),
+            message: "Connection refused".to_string(),
+            custom: HashMap::new(),
+            branch: "".to_string(),
+            env: "prod".to_string(),
+            category: "Database".to_string(),
+        }
+    }
+
+    type WebhookState = (Arc<Mutex<Vec<Value>>>, Arc<Mutex<usize>>);
+
+    async fn webhook(
+        State((received, failures)): State<WebhookState>,
+        Json(body): Json<Value>,
+    ) -> StatusCode {
+        received.lock().unwrap().push(body);
+        let mut failures = failures.lock().unwrap();
+        if *failures > 0 {
+            *failures -= 1;
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+        StatusCode::NO_CONTENT
+    }
+
+    /// Starts a local webhook that fails the first `failures` requests and records
+    /// every payload it receives.
+    async fn mock_webhook(failures: usize) -> (String, Arc<Mutex<Vec<Value>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let app = Router::new()
+            .route("/hook", post(webhook))
+            .with_state((received.clone(), Arc::new(Mutex::new(failures))));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, received)
+    }
+
+    #[tokio::test]
+    async fn delivers_discord_embed() {
+        let (url, received) = mock_webhook(0).await;
+        let payload = payload(Provider::Discord, "billing", &test_log());
+        deliver(&reqwest::Client::new(), &url, &payload, 1, Duration::ZERO)
+            .await
+            .unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let embed = &received[0]["embeds"][0];
+        assert_eq!(embed["title"], "[Error] billing (prod)");
+        assert_eq!(embed["description"], "Connection refused");
+        assert_eq!(embed["color"], 0xE53935);
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors() {
+        let (url, received) = mock_webhook(2).await;
+        let payload = payload(Provider::Slack, "billing", &test_log());
+        deliver(&reqwest::Client::new(), &url, &payload, 3, Duration::ZERO)
+            .await
+            .unwrap();
+
+        assert_eq!(received.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let (url, received) = mock_webhook(5).await;
+        let payload = payload(Provider::Teams, "billing", &test_log());
+        let result = deliver(&reqwest::Client::new(), &url, &payload, 2, Duration::ZERO).await;
+
+        assert!(result.is_err());
+        assert_eq!(received.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn waits_as_long_as_retry_after_asks() {
+        let requests = Arc::new(Mutex::new(0));
+        let app = Router::new()
+            .route(
+                "/hook",
+                post(|State(requests): State<Arc<Mutex<usize>>>| async move {
+                    let mut requests = requests.lock().unwrap();
+                    *requests += 1;
+                    if *requests == 1 {
+                        return (StatusCode::TOO_MANY_REQUESTS, [("Retry-After", "1")]);
+                    }
+                    (StatusCode::NO_CONTENT, [("Retry-After", "")])
+                }),
+            )
+            .with_state(requests.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let payload = payload(Provider::Discord, "billing", &test_log());
+        let started = std::time::Instant::now();
+        deliver(&reqwest::Client::new(), &url, &payload, 2, Duration::ZERO)
+            .await
+            .unwrap();
+
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(*requests.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn builds_provider_payloads() {
+        let log = test_log();
+
+        let slack = payload(Provider::Slack, "billing", &log);
+        assert_eq!(slack["blocks"][0]["text"]["text"], "[Error] billing (prod)");
+        assert_eq!(
+            slack["blocks"][1]["text"]["text"],
+            "```Connection refused```"
+        );
+
+        let teams = payload(Provider::Teams, "billing", &log);
+        assert_eq!(teams["@type"], "MessageCard");
+        assert_eq!(teams["themeColor"], "E53935");
+        assert_eq!(teams["sections"][0]["facts"][0]["name"], "Timestamp");
+    }
+}
@@ -0,0 +1,587 @@
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use chrono::{DateTime, SecondsFormat, Utc};
+use prost::Message;
+use serde_json::{json, Value};
+
+use crate::ingest::empty_log;
+use crate::{ErrorLevel, Log};
+
+// The subset of the OTLP protobuf schema used by the logs service, from
+// opentelemetry/proto/{collector/logs/v1,logs/v1,resource/v1,common/v1}.
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ExportLogsServiceRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub resource_logs: Vec<ResourceLogs>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ExportLogsServiceResponse {
+    #[prost(message, optional, tag = "1")]
+    pub partial_success: Option<ExportLogsPartialSuccess>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ExportLogsPartialSuccess {
+    #[prost(int64, tag = "1")]
+    pub rejected_log_records: i64,
+    #[prost(string, tag = "2")]
+    pub error_message: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ResourceLogs {
+    #[prost(message, optional, tag = "1")]
+    pub resource: Option<Resource>,
+    #[prost(message, repeated, tag = "2")]
+    pub scope_logs: Vec<ScopeLogs>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Resource {
+    #[prost(message, repeated, tag = "1")]
+    pub attributes: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ScopeLogs {
+    #[prost(message, repeated, tag = "2")]
+    pub log_records: Vec<LogRecord>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct LogRecord {
+    #[prost(fixed64, tag = "1")]
+    pub time_unix_nano: u64,
+    #[prost(fixed64, tag = "11")]
+    pub observed_time_unix_nano: u64,
+    #[prost(int32, tag = "2")]
+    pub severity_number: i32,
+    #[prost(string, tag = "3")]
+    pub severity_text: String,
+    #[prost(message, optional, tag = "5")]
+    pub body: Option<AnyValue>,
+    #[prost(message, repeated, tag = "6")]
+    pub attributes: Vec<KeyValue>,
+    #[prost(bytes = "vec", tag = "9")]
+    pub trace_id: Vec<u8>,
+    #[prost(bytes = "vec", tag = "10")]
+    pub span_id: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct KeyValue {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(message, optional, tag = "2")]
+    pub value: Option<AnyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct AnyValue {
+    #[prost(oneof = "any_value::Value", tags = "1, 2, 3, 4, 5, 6, 7")]
+    pub value: Option<any_value::Value>,
+}
+
+pub mod any_value {
+    #[allow(clippy::enum_variant_names)]
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Value {
+        #[prost(string, tag = "1")]
+        StringValue(String),
+        #[prost(bool, tag = "2")]
+        BoolValue(bool),
+        #[prost(int64, tag = "3")]
+        IntValue(i64),
+        #[prost(double, tag = "4")]
+        DoubleValue(f64),
+        #[prost(message, tag = "5")]
+        ArrayValue(super::ArrayValue),
+        #[prost(message, tag = "6")]
+        KvlistValue(super::KeyValueList),
+        #[prost(bytes = "vec", tag = "7")]
+        BytesValue(Vec<u8>),
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ArrayValue {
+    #[prost(message, repeated, tag = "1")]
+    pub values: Vec<AnyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct KeyValueList {
+    #[prost(message, repeated, tag = "1")]
+    pub values: Vec<KeyValue>,
+}
+
+/// Encoding of an OTLP/HTTP request, picked by its `Content-Type`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Protobuf,
+    Json,
+}
+
+impl Encoding {
+    pub fn from_content_type(content_type: &str) -> Option<Encoding> {
+        match content_type.split(';').next().unwrap_or("").trim() {
+            "application/x-protobuf" => Some(Encoding::Protobuf),
+            "application/json" => Some(Encoding::Json),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Encoding::Protobuf => "application/x-protobuf",
+            Encoding::Json => "application/json",
+        }
+    }
+}
+
+/// Logs of one OTLP resource, which the `service.name` attribute ties to an app.
+pub struct ResourceBatch {
+    pub service_name: Option<String>,
+    pub environment: Option<String>,
+    pub logs: Vec<Log>,
+}
+
+pub fn decode(encoding: Encoding, body: &[u8]) -> Result<ExportLogsServiceRequest> {
+    match encoding {
+        Encoding::Protobuf => Ok(ExportLogsServiceRequest::decode(body)?),
+        Encoding::Json => request_from_json(&serde_json::from_slice(body)?),
+    }
+}
+
+/// Encodes the response, reporting rejected records as a partial success.
+pub fn encode_response(encoding: Encoding, rejected: usize, error_message: &str) -> Vec<u8> {
+    let partial_success = (rejected > 0).then(|| ExportLogsPartialSuccess {
+        rejected_log_records: rejected as i64,
+        error_message: error_message.to_string(),
+    });
+    match encoding {
+        Encoding::Protobuf => ExportLogsServiceResponse { partial_success }.encode_to_vec(),
+        Encoding::Json => {
+            let response = match partial_success {
+                Some(partial) => json!({
+                    "partialSuccess": {
+                        "rejectedLogRecords": partial.rejected_log_records.to_string(),
+                        "errorMessage": partial.error_message,
+                    }
+                }),
+                None => json!({}),
+            };
+            response.to_string().into_bytes()
+        }
+    }
+}
+
+/// Maps every log record onto a `Log`, grouped by resource.
+pub fn resource_batches(request: ExportLogsServiceRequest) -> Vec<ResourceBatch> {
+    request
+        .resource_logs
+        .into_iter()
+        .map(|resource_logs| {
+            let attributes = resource_logs
+                .resource
+                .map(|resource| resource.attributes)
+                .unwrap_or_default();
+            let attribute = |names: &[&str]| {
+                attributes
+                    .iter()
+                    .find(|attribute| names.contains(&attribute.key.as_str()))
+                    .map(|attribute| any_value_to_string(attribute.value.as_ref()))
+                    .filter(|value| !value.is_empty())
+            };
+            ResourceBatch {
+                service_name: attribute(&["service.name"]),
+                environment: attribute(&["deployment.environment.name", "deployment.environment"]),
+                logs: resource_logs
+                    .scope_logs
+                    .into_iter()
+                    .flat_map(|scope_logs| scope_logs.log_records)
+                    .map(create_log)
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+fn create_log(record: LogRecord) -> Log {
+    let mut new_log = empty_log();
+    new_log.level = match record.severity_number {
+        1..=8 => ErrorLevel::Debug,
+        9..=12 => ErrorLevel::Info,
+        13..=16 => ErrorLevel::Warning,
+        17..=20 => ErrorLevel::Error,
+        21..=24 => ErrorLevel::Critical,
+        _ => ErrorLevel::from_alias(&record.severity_text).unwrap_or(ErrorLevel::Unknown),
+    };
+
+    let nanos = match record.time_unix_nano {
+        0 => record.observed_time_unix_nano,
+        nanos => nanos,
+    };
+    let timestamp = match nanos {
+        0 => Utc::now(),
+        nanos => DateTime::from_timestamp_nanos(nanos as i64),
+    };
+    new_log.timestamp = timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true);
+    new_log.message = any_value_to_string(record.body.as_ref());
+
+    if record.trace_id.iter().any(|byte| *byte != 0) {
+        new_log.branch = hex::encode(&record.trace_id);
+    }
+    if record.span_id.iter().any(|byte| *byte != 0) {
+        new_log
+            .custom
+            .insert("span_id".to_string(), hex::encode(&record.span_id));
+    }
+
+    // The code.* semantic conventions describe where the log came from.
+    for attribute in record.attributes {
+        let value = any_value_to_string(attribute.value.as_ref());
+        match attribute.key.as_str() {
+            "code.filepath" | "code.file.path" => new_log.file = value,
+            "code.function" | "code.function.name" => new_log.function = value,
+            "code.lineno" | "code.line.number" => new_log.line = value.parse().ok(),
+            _ => {
+                new_log.custom.insert(attribute.key, value);
+            }
+        }
+    }
+    new_log
+}
+
+/// Strings are kept as they are, every other value is stored as JSON.
+fn any_value_to_string(value: Option<&AnyValue>) -> String {
+    match any_value_to_json(value) {
+        Value::String(value) => value,
+        Value::Null => "".to_string(),
+        value => value.to_string(),
+    }
+}
+
+fn any_value_to_json(value: Option<&AnyValue>) -> Value {
+    use any_value::Value as V;
+    match value.and_then(|value| value.value.as_ref()) {
+        None => Value::Null,
+        Some(V::StringValue(value)) => json!(value),
+        Some(V::BoolValue(value)) => json!(value),
+        Some(V::IntValue(value)) => json!(value),
+        Some(V::DoubleValue(value)) => json!(value),
+        Some(V::BytesValue(value)) => {
+            json!(base64::engine::general_purpose::STANDARD.encode(value))
+        }
+        Some(V::ArrayValue(array)) => Value::Array(
+            array
+                .values
+                .iter()
+                .map(|value| any_value_to_json(Some(value)))
+                .collect(),
+        ),
+        Some(V::KvlistValue(list)) => Value::Object(
+            list.values
+                .iter()
+                .map(|kv| (kv.key.clone(), any_value_to_json(kv.value.as_ref())))
+                .collect(),
+        ),
+    }
+}
+
+// OTLP/JSON follows the protobuf JSON mapping, except that trace and span IDs
+// are hex encoded. Field names are lowerCamelCase but snake_case is accepted
+// too, and 64-bit integers may be strings or numbers.
+
+fn field<'a>(value: &'a Value, name: &str) -> Option<&'a Value> {
+    let object = value.as_object()?;
+    object.get(name).or_else(|| {
+        let snake: String = name
+            .chars()
+            .flat_map(|c| match c.is_ascii_uppercase() {
+                true => vec!['_', c.to_ascii_lowercase()],
+                false => vec![c],
+            })
+            .collect();
+        object.get(&snake)
+    })
+}
+
+fn array<'a>(value: &'a Value, name: &str) -> &'a [Value] {
+    field(value, name)
+        .and_then(|value| value.as_array())
+        .map(|values| values.as_slice())
+        .unwrap_or_default()
+}
+
+fn string(value: &Value, name: &str) -> String {
+    field(value, name)
+        .and_then(|value| value.as_str())
+        .unwrap_or("")
+        .to_string()
+}
+
+fn integer(value: &Value, name: &str) -> Result<i128> {
+    match field(value, name) {
+        None | Some(Value::Null) => Ok(0),
+        Some(Value::Number(number)) => number
+            .as_i64()
+            .map(i128::from)
+            .or_else(|| number.as_u64().map(i128::from))
+            .ok_or_else(|| anyhow!("{} is not an integer", name)),
+        Some(Value::String(number)) => number
+            .parse()
+            .map_err(|_| anyhow!("{} is not an integer", name)),
+        Some(_) => Err(anyhow!("{} is not an integer", name)),
+    }
+}
+
+fn request_from_json(value: &Value) -> Result<ExportLogsServiceRequest> {
+    if !value.is_object() {
+        return Err(anyhow!("Body is not a JSON object"));
+    }
+    let mut request = ExportLogsServiceRequest::default();
+    for resource_logs in array(value, "resourceLogs") {
+        let resource = field(resource_logs, "resource")
+            .map(|resource| -> Result<Resource> {
+                Ok(Resource {
+                    attributes: key_values_from_json(array(resource, "attributes"))?,
+                })
+            })
+            .transpose()?;
+        let mut scope_logs = Vec::new();
+        for scope in array(resource_logs, "scopeLogs") {
+            let log_records = array(scope, "logRecords")
+                .iter()
+                .map(log_record_from_json)
+                .collect::<Result<Vec<LogRecord>>>()?;
+            scope_logs.push(ScopeLogs { log_records });
+        }
+        request.resource_logs.push(ResourceLogs {
+            resource,
+            scope_logs,
+        });
+    }
+    Ok(request)
+}
+
+fn log_record_from_json(value: &Value) -> Result<LogRecord> {
+    let id = |name: &str| -> Result<Vec<u8>> {
+        hex::decode(string(value, name)).map_err(|_| anyhow!("{} is not hex encoded", name))
+    };
+    Ok(LogRecord {
+        time_unix_nano: integer(value, "timeUnixNano")? as u64,
+        observed_time_unix_nano: integer(value, "observedTimeUnixNano")? as u64,
+        severity_number: integer(value, "severityNumber")? as i32,
+        severity_text: string(value, "severityText"),
+        body: field(value, "body").map(any_value_from_json).transpose()?,
+        attributes: key_values_from_json(array(value, "attributes"))?,
+        trace_id: id("traceId")?,
+        span_id: id("spanId")?,
+    })
+}
+
+fn key_values_from_json(values: &[Value]) -> Result<Vec<KeyValue>> {
+    values
+        .iter()
+        .map(|value| {
+            Ok(KeyValue {
+                key: string(value, "key"),
+                value: field(value, "value").map(any_value_from_json).transpose()?,
+            })
+        })
+        .collect()
+}
+
+fn any_value_from_json(value: &Value) -> Result<AnyValue> {
+    use any_value::Value as V;
+    let inner = if let Some(value) = field(value, "stringValue") {
+        Some(V::StringValue(value.as_str().unwrap_or("").to_string()))
+    } else if let Some(value) = field(value, "boolValue") {
+        Some(V::BoolValue(value.as_bool().unwrap_or(false)))
+    } else if field(value, "intValue").is_some() {
+        Some(V::IntValue(integer(value, "intValue")? as i64))
+    } else if let Some(number) = field(value, "doubleValue") {
+        let number = match number {
+            Value::String(number) => number.parse().ok(),
+            number => number.as_f64(),
+        };
+        Some(V::DoubleValue(number.unwrap_or(0.0)))
+    } else if let Some(array) = field(value, "arrayValue") {
+        Some(V::ArrayValue(ArrayValue {
+            values: crate::otlp::array(array, "values")
+                .iter()
+                .map(any_value_from_json)
+                .collect::<Result<Vec<AnyValue>>>()?,
+        }))
+    } else if let Some(list) = field(value, "kvlistValue") {
+        Some(V::KvlistValue(KeyValueList {
+            values: key_values_from_json(crate::otlp::array(list, "values"))?,
+        }))
+    } else if let Some(bytes) = field(value, "bytesValue") {
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(bytes.as_str().unwrap_or(""))
+            .map_err(|_| anyhow!("bytesValue is not base64 encoded"))?;
+        Some(V::BytesValue(bytes))
+    } else {
+        None
+    };
+    Ok(AnyValue { value: inner })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string_value(value: &str) -> Option<AnyValue> {
+        Some(AnyValue {
+            value: Some(any_value::Value::StringValue(value.to_string())),
+        })
+    }
+
+    fn attribute(key: &str, value: Option<AnyValue>) -> KeyValue {
+        KeyValue {
+            key: key.to_string(),
+            value,
+        }
+    }
+
+    fn export_request() -> ExportLogsServiceRequest {
+        ExportLogsServiceRequest {
+            resource_logs: vec![ResourceLogs {
+                resource: Some(Resource {
+                    attributes: vec![
+                        attribute("service.name", string_value("Checkout API")),
+                        attribute("deployment.environment", string_value("staging")),
+                    ],
+                }),
+                scope_logs: vec![ScopeLogs {
+                    log_records: vec![LogRecord {
+                        time_unix_nano: 1_712_393_304_000_000_000,
+                        observed_time_unix_nano: 0,
+                        severity_number: 17,
+                        severity_text: "ERROR".to_string(),
+                        body: string_value("Payment declined"),
+                        attributes: vec![
+                            attribute("code.filepath", string_value("src/pay.ts")),
+                            attribute(
+                                "code.lineno",
+                                Some(AnyValue {
+                                    value: Some(any_value::Value::IntValue(42)),
+                                }),
+                            ),
+                            attribute("order.id", string_value("A-1001")),
+                            attribute(
+                                "retry",
+                                Some(AnyValue {
+                                    value: Some(any_value::Value::BoolValue(true)),
+                                }),
+                            ),
+                        ],
+                        trace_id: hex::decode("5b8efff798038103d269b633813fc60c").unwrap(),
+                        span_id: hex::decode("eee19b7ec3c1b174").unwrap(),
+                    }],
+                }],
+            }],
+        }
+    }
+
+    fn assert_mapped(batches: Vec<ResourceBatch>) {
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.service_name.as_deref(), Some("Checkout API"));
+        assert_eq!(batch.environment.as_deref(), Some("staging"));
+
+        let log = &batch.logs[0];
+        assert_eq!(log.level, ErrorLevel::Error);
+        assert_eq!(log.timestamp, "2024-04-06T08:48:24Z");
+        assert_eq!(log.message, "Payment declined");
+        assert_eq!(log.branch, "5b8efff798038103d269b633813fc60c");
+        assert_eq!(log.file, "src/pay.ts");
+        assert_eq!(log.line, Some(42));
+        assert_eq!(log.custom["order.id"], "A-1001");
+        assert_eq!(log.custom["retry"], "true");
+        assert_eq!(log.custom["span_id"], "eee19b7ec3c1b174");
+    }
+
+    #[test]
+    fn maps_protobuf_payloads() {
+        let body = export_request().encode_to_vec();
+        let request = decode(Encoding::Protobuf, &body).unwrap();
+        assert_mapped(resource_batches(request));
+    }
+
+    #[test]
+    fn maps_json_payloads() {
+        let body = json!({
+            "resourceLogs": [{
+                "resource": {
+                    "attributes": [
+                        { "key": "service.name", "value": { "stringValue": "Checkout API" } },
+                        { "key": "deployment.environment", "value": { "stringValue": "staging" } }
+                    ]
+                },
+                "scopeLogs": [{
+                    "scope": { "name": "checkout" },
+                    "logRecords": [{
+                        "timeUnixNano": "1712393304000000000",
+                        "severityNumber": 17,
+                        "severityText": "ERROR",
+                        "body": { "stringValue": "Payment declined" },
+                        "attributes": [
+                            { "key": "code.filepath", "value": { "stringValue": "src/pay.ts" } },
+                            { "key": "code.lineno", "value": { "intValue": "42" } },
+                            { "key": "order.id", "value": { "stringValue": "A-1001" } },
+                            { "key": "retry", "value": { "boolValue": true } }
+                        ],
+                        "traceId": "5b8efff798038103d269b633813fc60c",
+                        "spanId": "eee19b7ec3c1b174"
+                    }]
+                }]
+            }]
+        });
+        let request = decode(Encoding::Json, body.to_string().as_bytes()).unwrap();
+        assert_mapped(resource_batches(request));
+    }
+
+    #[test]
+    fn maps_severity_numbers_and_structured_bodies() {
+        let mut request = export_request();
+        let record = &mut request.resource_logs[0].scope_logs[0].log_records[0];
+        record.body = Some(AnyValue {
+            value: Some(any_value::Value::KvlistValue(KeyValueList {
+                values: vec![attribute("event", string_value("login"))],
+            })),
+        });
+
+        for (severity_number, severity_text, expected) in [
+            (1, "", ErrorLevel::Debug),
+            (9, "", ErrorLevel::Info),
+            (13, "", ErrorLevel::Warning),
+            (21, "", ErrorLevel::Critical),
+            (0, "warn", ErrorLevel::Warning),
+            (0, "", ErrorLevel::Unknown),
+        ] {
+            let mut request = request.clone();
+            let record = &mut request.resource_logs[0].scope_logs[0].log_records[0];
+            record.severity_number = severity_number;
+            record.severity_text = severity_text.to_string();
+            let batches = resource_batches(request);
+            assert_eq!(batches[0].logs[0].level, expected);
+            assert_eq!(batches[0].logs[0].message, r#"{"event":"login"}"#);
+        }
+    }
+
+    #[test]
+    fn encodes_partial_success() {
+        let response = encode_response(Encoding::Protobuf, 2, "unknown app");
+        let response = ExportLogsServiceResponse::decode(response.as_slice()).unwrap();
+        let partial = response.partial_success.unwrap();
+        assert_eq!(partial.rejected_log_records, 2);
+        assert_eq!(partial.error_message, "unknown app");
+
+        assert_eq!(encode_response(Encoding::Json, 0, ""), b"{}");
+    }
+}
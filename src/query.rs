@@ -0,0 +1,939 @@
+use anyhow::Result;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::search;
+use crate::{ErrorLevel, Log};
+
+/// Fields a query can name, `custom.<key>` names a custom data key.
+const FIELDS: [&str; 11] = [
+    "level",
+    "message",
+    "file",
+    "function",
+    "line",
+    "timestamp",
+    "category",
+    "branch",
+    "env",
+    "uid",
+    "custom.<key>",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Field {
+    Level,
+    Message,
+    File,
+    Function,
+    Line,
+    Timestamp,
+    Category,
+    Branch,
+    Env,
+    Uid,
+    Custom(String),
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Field> {
+        let field = match name.to_lowercase().as_str() {
+            "level" => Field::Level,
+            "message" => Field::Message,
+            "file" => Field::File,
+            "function" => Field::Function,
+            "line" => Field::Line,
+            "timestamp" => Field::Timestamp,
+            "category" => Field::Category,
+            "branch" => Field::Branch,
+            "env" => Field::Env,
+            "uid" => Field::Uid,
+            _ => {
+                // Custom keys keep the case they were written with.
+                let (prefix, key) = name.split_once('.')?;
+                if !prefix.eq_ignore_ascii_case("custom") || key.is_empty() {
+                    return None;
+                }
+                Field::Custom(key.to_string())
+            }
+        };
+        Some(field)
+    }
+
+    fn is_comparable(&self) -> bool {
+        matches!(self, Field::Line | Field::Timestamp)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Equal,
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Bound {
+    Line(u32),
+    Timestamp(DateTime<Utc>),
+}
+
+/// A parsed query. Bare words are looked up in the day's search index, every
+/// other condition is checked against the logs in the ledger.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Query {
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+    Not(Box<Query>),
+    Text(String),
+    Level(ErrorLevel),
+    Match(Field, String),
+    Compare(Field, Comparison, Bound),
+}
+
+/// Why a query couldn't be parsed. `position` is the character the problem
+/// was found at, counting from 0.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SyntaxError {
+    pub error: String,
+    pub position: usize,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at position {}", self.error, self.position)
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
+fn syntax_error(position: usize, error: String) -> SyntaxError {
+    SyntaxError { error, position }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    LeftParen,
+    RightParen,
+    And,
+    Or,
+    Not,
+    Text(String),
+    Field {
+        name: String,
+        comparison: Comparison,
+        value: Value,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Single(String),
+    Range(String, String),
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::LeftParen => "`(`".to_string(),
+        Token::RightParen => "`)`".to_string(),
+        Token::And => "`AND`".to_string(),
+        Token::Or => "`OR`".to_string(),
+        Token::Not => "`NOT`".to_string(),
+        Token::Text(text) => format!("`{}`", text),
+        Token::Field { name, .. } => format!("`{}:`", name),
+    }
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Lexer {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn is_separator(c: char) -> bool {
+        c.is_whitespace() || c == '(' || c == ')' || c == '"'
+    }
+
+    /// Reads characters up to whitespace, a parenthesis or a quote.
+    fn word(&mut self) -> String {
+        let start = self.position;
+        while self.peek().is_some_and(|c| !Lexer::is_separator(c)) {
+            self.position += 1;
+        }
+        self.chars[start..self.position].iter().collect()
+    }
+
+    /// Reads a quoted string, the opening quote is the current character.
+    fn quoted(&mut self) -> Result<String, SyntaxError> {
+        let start = self.position;
+        self.position += 1;
+        let mut text = String::new();
+        loop {
+            match self.peek() {
+                None => return Err(syntax_error(start, "Unterminated quote".to_string())),
+                Some('"') => {
+                    self.position += 1;
+                    return Ok(text);
+                }
+                Some('\\') if self.chars.get(self.position + 1).is_some() => {
+                    text.push(self.chars[self.position + 1]);
+                    self.position += 2;
+                }
+                Some(c) => {
+                    text.push(c);
+                    self.position += 1;
+                }
+            }
+        }
+    }
+
+    /// Reads `[from TO to]`, the opening bracket is the current character.
+    fn range(&mut self) -> Result<Value, SyntaxError> {
+        let start = self.position;
+        let Some(length) = self.chars[start..].iter().position(|c| *c == ']') else {
+            return Err(syntax_error(start, "Unterminated range".to_string()));
+        };
+        let inner: String = self.chars[start + 1..start + length].iter().collect();
+        self.position = start + length + 1;
+        let bounds: Vec<&str> = inner.split_whitespace().collect();
+        match bounds.as_slice() {
+            [from, "TO", to] => Ok(Value::Range(from.to_string(), to.to_string())),
+            _ => Err(syntax_error(
+                start,
+                format!("Expected a range like [1 TO 10], found `[{}]`", inner),
+            )),
+        }
+    }
+
+    /// Reads the comparison and value following `name:`.
+    fn field(&mut self, name: String, start: usize) -> Result<Token, SyntaxError> {
+        let comparison = match (self.peek(), self.chars.get(self.position + 1)) {
+            (Some('>'), Some('=')) => Comparison::GreaterOrEqual,
+            (Some('<'), Some('=')) => Comparison::LessOrEqual,
+            (Some('>'), _) => Comparison::Greater,
+            (Some('<'), _) => Comparison::Less,
+            _ => Comparison::Equal,
+        };
+        self.position += match comparison {
+            Comparison::Equal => 0,
+            Comparison::Greater | Comparison::Less => 1,
+            Comparison::GreaterOrEqual | Comparison::LessOrEqual => 2,
+        };
+
+        let value = match self.peek() {
+            Some('"') => Value::Single(self.quoted()?),
+            Some('[') if comparison == Comparison::Equal => self.range()?,
+            _ => Value::Single(self.word()),
+        };
+        if value == Value::Single(String::new()) {
+            return Err(syntax_error(
+                start,
+                format!("Expected a value after `{}:`", name),
+            ));
+        }
+        Ok(Token::Field {
+            name,
+            comparison,
+            value,
+        })
+    }
+
+    fn tokens(mut self) -> Result<Vec<(usize, Token)>, SyntaxError> {
+        let mut tokens = Vec::new();
+        while let Some(c) = self.peek() {
+            let start = self.position;
+            let token = match c {
+                c if c.is_whitespace() => {
+                    self.position += 1;
+                    continue;
+                }
+                '(' => {
+                    self.position += 1;
+                    Token::LeftParen
+                }
+                ')' => {
+                    self.position += 1;
+                    Token::RightParen
+                }
+                '"' => Token::Text(self.quoted()?),
+                _ => {
+                    let word_end = self.chars[start..]
+                        .iter()
+                        .position(|c| Lexer::is_separator(*c) || *c == ':')
+                        .map_or(self.chars.len(), |length| start + length);
+                    if self.chars.get(word_end) == Some(&':') {
+                        let name: String = self.chars[start..word_end].iter().collect();
+                        self.position = word_end + 1;
+                        self.field(name, start)?
+                    } else {
+                        match self.word().as_str() {
+                            "AND" => Token::And,
+                            "OR" => Token::Or,
+                            "NOT" => Token::Not,
+                            word => Token::Text(word.to_string()),
+                        }
+                    }
+                }
+            };
+            tokens.push((start, token));
+        }
+        Ok(tokens)
+    }
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    /// Length of the query, where errors about its end point to.
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, token)| token)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.next)
+            .map_or(self.end, |(position, _)| *position)
+    }
+
+    fn or(&mut self) -> Result<Query, SyntaxError> {
+        let mut query = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next += 1;
+            query = Query::Or(Box::new(query), Box::new(self.and()?));
+        }
+        Ok(query)
+    }
+
+    /// Terms next to each other without an operator must all match.
+    fn and(&mut self) -> Result<Query, SyntaxError> {
+        let mut query = self.not()?;
+        loop {
+            match self.peek() {
+                Some(Token::And) => self.next += 1,
+                None | Some(Token::Or) | Some(Token::RightParen) => return Ok(query),
+                Some(_) => {}
+            }
+            query = Query::And(Box::new(query), Box::new(self.not()?));
+        }
+    }
+
+    fn not(&mut self) -> Result<Query, SyntaxError> {
+        if self.peek() == Some(&Token::Not) {
+            self.next += 1;
+            return Ok(Query::Not(Box::new(self.not()?)));
+        }
+        self.term()
+    }
+
+    fn term(&mut self) -> Result<Query, SyntaxError> {
+        let position = self.position();
+        let Some((_, token)) = self.tokens.get(self.next).cloned() else {
+            let error = match self.next.checked_sub(1).map(|last| &self.tokens[last].1) {
+                Some(last) => format!("Expected a search term after {}", describe(last)),
+                None => "Query is empty".to_string(),
+            };
+            return Err(syntax_error(self.end, error));
+        };
+        self.next += 1;
+        match token {
+            Token::LeftParen => {
+                let query = self.or()?;
+                if self.peek() != Some(&Token::RightParen) {
+                    return Err(syntax_error(
+                        self.position(),
+                        format!("Expected `)` to close the `(` at position {}", position),
+                    ));
+                }
+                self.next += 1;
+                Ok(query)
+            }
+            Token::Text(text) => Ok(Query::Text(text)),
+            Token::Field {
+                name,
+                comparison,
+                value,
+            } => field_query(&name, comparison, value, position),
+            token => Err(syntax_error(
+                position,
+                format!("Expected a search term, found {}", describe(&token)),
+            )),
+        }
+    }
+}
+
+fn field_query(
+    name: &str,
+    comparison: Comparison,
+    value: Value,
+    position: usize,
+) -> Result<Query, SyntaxError> {
+    let Some(field) = Field::parse(name) else {
+        return Err(syntax_error(
+            position,
+            format!(
+                "Unknown field `{}`, expected one of {}",
+                name,
+                FIELDS.join(", ")
+            ),
+        ));
+    };
+    let compares = comparison != Comparison::Equal || matches!(value, Value::Range(..));
+    if compares && !field.is_comparable() {
+        return Err(syntax_error(
+            position,
+            format!(
+                "`{}` can't be compared, only `line` and `timestamp` can",
+                name
+            ),
+        ));
+    }
+
+    match value {
+        Value::Range(from, to) => Ok(Query::And(
+            Box::new(Query::Compare(
+                field.clone(),
+                Comparison::GreaterOrEqual,
+                bound(&field, &from, position)?,
+            )),
+            Box::new(Query::Compare(
+                field.clone(),
+                Comparison::LessOrEqual,
+                bound(&field, &to, position)?,
+            )),
+        )),
+        Value::Single(value) if field.is_comparable() => Ok(Query::Compare(
+            field.clone(),
+            comparison,
+            bound(&field, &value, position)?,
+        )),
+        Value::Single(value) if field == Field::Level => {
+            let level = ErrorLevel::from_filter(&value)
+                .ok_or_else(|| syntax_error(position, format!("Unknown level `{}`", value)))?;
+            Ok(Query::Level(level))
+        }
+        Value::Single(value) => Ok(Query::Match(field, value)),
+    }
+}
+
+fn bound(field: &Field, value: &str, position: usize) -> Result<Bound, SyntaxError> {
+    match field {
+        Field::Line => value.parse::<u32>().map(Bound::Line).map_err(|_| {
+            syntax_error(
+                position,
+                format!("`line` must be compared to a number, found `{}`", value),
+            )
+        }),
+        _ => parse_timestamp(value).map(Bound::Timestamp).ok_or_else(|| {
+            syntax_error(
+                position,
+                format!(
+                    "Invalid timestamp `{}`, expected one like 2024-04-06T08:48:24Z or 2024-04-06",
+                    value
+                ),
+            )
+        }),
+    }
+}
+
+/// Reads RFC 3339 timestamps. Timestamps without an offset and plain dates
+/// (midnight) are UTC.
+pub fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Some(timestamp.with_timezone(&Utc));
+    }
+    if let Ok(timestamp) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f") {
+        return Some(timestamp.and_utc());
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc())
+}
+
+pub fn parse(query: &str) -> Result<Query, SyntaxError> {
+    let lexer = Lexer {
+        chars: query.chars().collect(),
+        position: 0,
+    };
+    let end = lexer.chars.len();
+    let mut parser = Parser {
+        tokens: lexer.tokens()?,
+        next: 0,
+        end,
+    };
+    let query = parser.or()?;
+    if let Some((position, token)) = parser.tokens.get(parser.next) {
+        return Err(syntax_error(
+            *position,
+            format!("Unexpected {}", describe(token)),
+        ));
+    }
+    Ok(query)
+}
+
+fn compare<T: PartialOrd>(value: T, comparison: Comparison, bound: T) -> bool {
+    match comparison {
+        Comparison::Equal => value == bound,
+        Comparison::Greater => value > bound,
+        Comparison::GreaterOrEqual => value >= bound,
+        Comparison::Less => value < bound,
+        Comparison::LessOrEqual => value <= bound,
+    }
+}
+
+fn contains(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(&needle.to_lowercase())
+}
+
+impl Query {
+    /// Bare words of the query, each is looked up in the index once.
+    fn texts<'a>(&'a self, texts: &mut Vec<&'a str>) {
+        match self {
+            Query::And(left, right) | Query::Or(left, right) => {
+                left.texts(texts);
+                right.texts(texts);
+            }
+            Query::Not(query) => query.texts(texts),
+            Query::Text(text) => texts.push(text),
+            _ => {}
+        }
+    }
+
+    /// Bare words that make a log rank higher, words after `NOT` don't.
+    fn ranking_texts<'a>(&'a self, texts: &mut Vec<&'a str>) {
+        match self {
+            Query::And(left, right) | Query::Or(left, right) => {
+                left.ranking_texts(texts);
+                right.ranking_texts(texts);
+            }
+            Query::Text(text) => texts.push(text),
+            _ => {}
+        }
+    }
+
+    /// Checks a log against the query. `hits` holds the uids the index found
+    /// for every bare word.
+    pub fn matches(&self, log: &Log, hits: &HashMap<&str, HashSet<String>>) -> bool {
+        match self {
+            Query::And(left, right) => left.matches(log, hits) && right.matches(log, hits),
+            Query::Or(left, right) => left.matches(log, hits) || right.matches(log, hits),
+            Query::Not(query) => !query.matches(log, hits),
+            Query::Text(text) => hits
+                .get(text.as_str())
+                .is_some_and(|uids| uids.contains(&log.uid)),
+            Query::Level(level) => log.level == *level,
+            Query::Match(field, value) => match field {
+                Field::Message => contains(&log.message, value),
+                Field::File => contains(&log.file, value),
+                Field::Function => contains(&log.function, value),
+                Field::Category => log.category.eq_ignore_ascii_case(value),
+                Field::Branch => log.branch.eq_ignore_ascii_case(value),
+                Field::Env => log.env.eq_ignore_ascii_case(value),
+                Field::Uid => log.uid.eq_ignore_ascii_case(value),
+                Field::Custom(key) => log
+                    .custom
+                    .get(key)
+                    .is_some_and(|custom| custom.eq_ignore_ascii_case(value)),
+                Field::Level | Field::Line | Field::Timestamp => false,
+            },
+            Query::Compare(_, comparison, Bound::Line(line)) => log
+                .line
+                .is_some_and(|value| compare(value, *comparison, *line)),
+            Query::Compare(_, comparison, Bound::Timestamp(timestamp)) => {
+                parse_timestamp(&log.timestamp)
+                    .is_some_and(|value| compare(value, *comparison, *timestamp))
+            }
+        }
+    }
+}
+
+/// Logs of a day matching the query, in the order they were written. Without
+/// a query every log matches.
+fn matching_logs(app_path: &Path, date: &str, query: Option<&Query>) -> Result<Vec<Log>> {
+    let ledger = app_path.join("ledgers").join(format!("{}.jsonl", date));
+    if !ledger.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut texts = Vec::new();
+    if let Some(query) = query {
+        query.texts(&mut texts);
+    }
+    let mut hits: HashMap<&str, HashSet<String>> = HashMap::new();
+    for text in texts {
+        if !hits.contains_key(text) {
+            hits.insert(
+                text,
+                search::search(app_path, date, text)?.into_iter().collect(),
+            );
+        }
+    }
+
+    Ok(fs::read_to_string(ledger)?
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Log>(line).ok())
+        .filter(|log| query.is_none_or(|query| query.matches(log, &hits)))
+        .collect())
+}
+
+/// Runs a query against a day of an app. Logs are returned in the order they
+/// were written, or best matches first when the query has bare words.
+pub fn run(app_path: &Path, date: &str, query: &Query) -> Result<Vec<Log>> {
+    let mut logs = matching_logs(app_path, date, Some(query))?;
+
+    let mut ranking_texts = Vec::new();
+    query.ranking_texts(&mut ranking_texts);
+    if !ranking_texts.is_empty() {
+        let ranks: HashMap<String, usize> =
+            search::search(app_path, date, &ranking_texts.join(" "))?
+                .into_iter()
+                .enumerate()
+                .map(|(rank, uid)| (uid, rank))
+                .collect();
+        // Logs matching only some of the words keep their order after the rest.
+        logs.sort_by_key(|log| ranks.get(&log.uid).copied().unwrap_or(usize::MAX));
+    }
+    Ok(logs)
+}
+
+/// The last log of a page, the next page starts after it. Logs are ordered by
+/// timestamp, then app and uid.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Cursor {
+    timestamp: DateTime<Utc>,
+    app: String,
+    uid: String,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap())
+    }
+
+    pub fn decode(cursor: &str) -> Option<Cursor> {
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Hit {
+    pub app: String,
+    #[serde(flatten)]
+    pub log: Log,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Page {
+    pub logs: Vec<Hit>,
+    /// Continues the search, `None` on the last page.
+    pub cursor: Option<String>,
+}
+
+/// Searches the logs of several apps written between two timestamps, oldest
+/// first. A page holds at most `limit` logs that come after `cursor`.
+pub fn find(
+    storage_path: &Path,
+    apps: &[String],
+    (from, to): (DateTime<Utc>, DateTime<Utc>),
+    query: Option<&Query>,
+    cursor: Option<&Cursor>,
+    limit: usize,
+) -> Result<Page> {
+    // Days are named after the date in each log's own offset, which is at most
+    // 14 hours away from UTC, so the days around the range are read as well.
+    // Later pages start from the day before the cursor.
+    let start = cursor.map_or(from, |cursor| cursor.timestamp.max(from));
+    let mut day = start.date_naive().pred_opt().unwrap_or(NaiveDate::MIN);
+    let last = to.date_naive().succ_opt().unwrap_or(NaiveDate::MAX);
+
+    let mut found: Vec<(Cursor, Hit)> = Vec::new();
+    while day <= last {
+        let date = day.format("%Y-%m-%d").to_string();
+        for app in apps {
+            for log in matching_logs(&storage_path.join(app), &date, query)? {
+                let Some(timestamp) = parse_timestamp(&log.timestamp) else {
+                    continue;
+                };
+                if timestamp < from || timestamp > to {
+                    continue;
+                }
+                let key = Cursor {
+                    timestamp,
+                    app: app.clone(),
+                    uid: log.uid.clone(),
+                };
+                if cursor.is_some_and(|cursor| key <= *cursor) {
+                    continue;
+                }
+                let hit = Hit {
+                    app: app.clone(),
+                    log,
+                };
+                found.push((key, hit));
+            }
+        }
+
+        // Later days only hold logs from after this day started, so the page
+        // is complete once enough logs are older than that.
+        let day_start = day.and_hms_opt(0, 0, 0).unwrap().and_utc();
+        let settled = found
+            .iter()
+            .filter(|(key, _)| key.timestamp < day_start)
+            .count();
+        if settled > limit {
+            break;
+        }
+        match day.succ_opt() {
+            Some(next) => day = next,
+            None => break,
+        }
+    }
+
+    found.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let mut cursor = None;
+    if found.len() > limit {
+        found.truncate(limit);
+        cursor = found.last().map(|(key, _)| key.encode());
+    }
+    Ok(Page {
+        logs: found.into_iter().map(|(_, hit)| hit).collect(),
+        cursor,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::empty_log;
+
+    fn text(text: &str) -> Box<Query> {
+        Box::new(Query::Text(text.to_string()))
+    }
+
+    #[test]
+    fn parses_fields_operators_and_precedence() {
+        let query = parse(
+            r#"level:error AND category:"Payments" AND custom.user_id:42 AND NOT message:timeout"#,
+        )
+        .unwrap();
+        assert_eq!(
+            query,
+            Query::And(
+                Box::new(Query::And(
+                    Box::new(Query::And(
+                        Box::new(Query::Level(ErrorLevel::Error)),
+                        Box::new(Query::Match(Field::Category, "Payments".to_string())),
+                    )),
+                    Box::new(Query::Match(
+                        Field::Custom("user_id".to_string()),
+                        "42".to_string()
+                    )),
+                )),
+                Box::new(Query::Not(Box::new(Query::Match(
+                    Field::Message,
+                    "timeout".to_string()
+                )))),
+            )
+        );
+
+        assert_eq!(
+            parse("a b OR c").unwrap(),
+            Query::Or(Box::new(Query::And(text("a"), text("b"))), text("c"))
+        );
+        assert_eq!(
+            parse("a (b OR c)").unwrap(),
+            Query::And(text("a"), Box::new(Query::Or(text("b"), text("c"))))
+        );
+        assert_eq!(
+            parse("line:>=10").unwrap(),
+            Query::Compare(Field::Line, Comparison::GreaterOrEqual, Bound::Line(10))
+        );
+        assert_eq!(
+            parse("timestamp:[2024-04-06 TO 2024-04-06T12:00:00+02:00]").unwrap(),
+            Query::And(
+                Box::new(Query::Compare(
+                    Field::Timestamp,
+                    Comparison::GreaterOrEqual,
+                    Bound::Timestamp(parse_timestamp("2024-04-06T00:00:00Z").unwrap()),
+                )),
+                Box::new(Query::Compare(
+                    Field::Timestamp,
+                    Comparison::LessOrEqual,
+                    Bound::Timestamp(parse_timestamp("2024-04-06T10:00:00Z").unwrap()),
+                )),
+            )
+        );
+        assert_eq!(
+            parse(r#""payment declined""#).unwrap(),
+            *text("payment declined")
+        );
+        assert_eq!(
+            parse("level:warn").unwrap(),
+            Query::Level(ErrorLevel::Warning)
+        );
+    }
+
+    #[test]
+    fn reports_syntax_errors() {
+        let error = |query| parse(query).unwrap_err();
+        assert_eq!(
+            error("level:error AND"),
+            syntax_error(15, "Expected a search term after `AND`".to_string())
+        );
+        assert_eq!(
+            error("(a OR b"),
+            syntax_error(7, "Expected `)` to close the `(` at position 0".to_string())
+        );
+        assert_eq!(error("a)"), syntax_error(1, "Unexpected `)`".to_string()));
+        assert_eq!(
+            error(r#"message:"unterminated"#),
+            syntax_error(8, "Unterminated quote".to_string())
+        );
+        assert_eq!(
+            error("lvl:error").error,
+            format!("Unknown field `lvl`, expected one of {}", FIELDS.join(", "))
+        );
+        assert_eq!(
+            error("a category:>b"),
+            syntax_error(
+                2,
+                "`category` can't be compared, only `line` and `timestamp` can".to_string()
+            )
+        );
+        assert_eq!(
+            error("line:ten").error,
+            "`line` must be compared to a number, found `ten`"
+        );
+        assert_eq!(error("level:loud").error, "Unknown level `loud`");
+        assert_eq!(error("file:").error, "Expected a value after `file:`");
+        assert_eq!(
+            error("line:[1 10]").error,
+            "Expected a range like [1 TO 10], found `[1 10]`"
+        );
+        assert_eq!(error("NOT").error, "Expected a search term after `NOT`");
+        assert_eq!(error("  ").error, "Query is empty");
+    }
+
+    #[test]
+    fn matches_logs() {
+        let mut log = empty_log();
+        log.level = ErrorLevel::Error;
+        log.category = "Payments".to_string();
+        log.message = "Card declined by the bank".to_string();
+        log.line = Some(69);
+        log.timestamp = "2024-04-06T08:48:24Z".to_string();
+        log.custom.insert("user_id".to_string(), "42".to_string());
+        let hits = HashMap::from([("bank", HashSet::from([log.uid.clone()]))]);
+
+        let matches = |query: &str| parse(query).unwrap().matches(&log, &hits);
+        assert!(matches(
+            "level:err AND category:payments AND custom.user_id:42 AND NOT message:timeout"
+        ));
+        assert!(!matches("custom.user_id:4"));
+        assert!(!matches("custom.missing:42"));
+        assert!(matches("message:DECLINED"));
+        assert!(matches("line:69 line:[60 TO 70] line:>68 line:<=69"));
+        assert!(!matches("line:>69"));
+        assert!(matches(
+            "timestamp:>=2024-04-06T08:00:00Z timestamp:<2024-04-07"
+        ));
+        assert!(!matches("timestamp:>2024-04-06T10:48:24+02:00"));
+        assert!(matches("bank"));
+        assert!(!matches("timeout"));
+        assert!(matches("timeout OR level:error"));
+    }
+
+    #[test]
+    fn pages_through_apps_and_days_in_timestamp_order() {
+        let storage_path =
+            std::env::temp_dir().join(format!("lumberjack-query-{}", uuid::Uuid::now_v7()));
+        let write = |app: &str, date: &str, timestamps: &[&str]| {
+            let ledgers = storage_path.join(app).join("ledgers");
+            fs::create_dir_all(&ledgers).unwrap();
+            let mut ledger = String::new();
+            for timestamp in timestamps {
+                let mut log = empty_log();
+                log.timestamp = timestamp.to_string();
+                log.message = format!("{} at {}", app, timestamp);
+                ledger.push_str(&serde_json::to_string(&log).unwrap());
+                ledger.push('\n');
+            }
+            fs::write(ledgers.join(format!("{}.jsonl", date)), ledger).unwrap();
+        };
+        write("shop", "2024-04-05", &["2024-04-05T23:00:00Z"]);
+        // The last timestamp is 2024-04-07T02:00:00Z in UTC.
+        write(
+            "shop",
+            "2024-04-06",
+            &[
+                "2024-04-06T10:00:00Z",
+                "2024-04-06T08:00:00Z",
+                "2024-04-06T21:00:00-05:00",
+            ],
+        );
+        write(
+            "blog",
+            "2024-04-06",
+            &["2024-04-06T09:00:00Z", "2024-04-06T12:00:00Z"],
+        );
+        write("blog", "2024-04-08", &["2024-04-08T01:00:00Z"]);
+
+        let apps = vec!["blog".to_string(), "shop".to_string()];
+        let range = (
+            parse_timestamp("2024-04-06").unwrap(),
+            parse_timestamp("2024-04-07T12:00:00Z").unwrap(),
+        );
+        let mut pages = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = find(&storage_path, &apps, range, None, cursor.as_ref(), 2).unwrap();
+            pages.push(
+                page.logs
+                    .iter()
+                    .map(|hit| hit.log.message.clone())
+                    .collect::<Vec<String>>(),
+            );
+            match page.cursor {
+                Some(next) => cursor = Some(Cursor::decode(&next).unwrap()),
+                None => break,
+            }
+        }
+        assert_eq!(
+            pages,
+            vec![
+                vec![
+                    "shop at 2024-04-06T08:00:00Z",
+                    "blog at 2024-04-06T09:00:00Z"
+                ],
+                vec![
+                    "shop at 2024-04-06T10:00:00Z",
+                    "blog at 2024-04-06T12:00:00Z"
+                ],
+                vec!["shop at 2024-04-06T21:00:00-05:00"],
+            ]
+        );
+
+        let query = parse("message:blog").unwrap();
+        let page = find(&storage_path, &apps, range, Some(&query), None, 10).unwrap();
+        assert_eq!(page.logs.len(), 2);
+        assert!(page.logs.iter().all(|hit| hit.app == "blog"));
+        assert!(page.cursor.is_none());
+        assert!(Cursor::decode("not a cursor").is_none());
+
+        fs::remove_dir_all(&storage_path).unwrap();
+    }
+}
@@ -0,0 +1,416 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, read_dir, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::archive::{self, ArchiveStore};
+use crate::config::{Config, Rentention};
+use crate::idempotency;
+use crate::search;
+use crate::{ErrorLevel, Log, CONFIG, LEDGER_LOCK};
+
+/// What started a retention run.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    Scheduled,
+    Manual,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RetentionReport {
+    pub trigger: Trigger,
+    pub mode: Rentention,
+    pub started_at: String,
+    pub finished_at: String,
+    /// `<app>/<date>` of every day removed from live storage without archiving.
+    pub deleted: Vec<String>,
+    /// `<app>/<date>` of every day moved into an archive.
+    pub archived: Vec<String>,
+    /// `<app>/<date>` of every day where only the entries covered by a shorter
+    /// retention policy were removed, with the number of entries.
+    pub pruned: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RetentionStatus {
+    pub interval_minutes: u64,
+    pub running: bool,
+    pub last_run: Option<RetentionReport>,
+    pub next_run: Option<String>,
+}
+
+lazy_static! {
+    static ref STATUS: Mutex<RetentionStatus> = Mutex::new(RetentionStatus::default());
+    /// Held for the duration of a run so scheduled and manual runs never overlap.
+    static ref RUNNING: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+pub fn status() -> RetentionStatus {
+    STATUS.lock().unwrap().clone()
+}
+
+/// Spawns the task that runs retention at startup and then every
+/// `retention_interval` minutes. An interval of 0 disables the scheduler.
+pub fn start(config: &Config) {
+    let interval_minutes = config.retention_interval;
+    STATUS.lock().unwrap().interval_minutes = interval_minutes;
+    if interval_minutes == 0 {
+        return;
+    }
+
+    let interval = Duration::from_secs(interval_minutes * 60);
+    tokio::spawn(async move {
+        loop {
+            let report = run(Trigger::Scheduled).await;
+            if report.errors.is_empty() {
+                println!(
+                    "Retention: deleted {}, archived {} and pruned {} day(s)",
+                    report.deleted.len(),
+                    report.archived.len(),
+                    report.pruned.len()
+                );
+            } else {
+                eprintln!(
+                    "Retention: deleted {}, archived {} and pruned {} day(s) with {} error(s)",
+                    report.deleted.len(),
+                    report.archived.len(),
+                    report.pruned.len(),
+                    report.errors.len()
+                );
+            }
+
+            let next_run = Utc::now() + interval;
+            STATUS.lock().unwrap().next_run = Some(next_run.to_rfc3339());
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+/// Deletes or archives every day older than the retention window, based on the
+/// current config. Failures are collected per day so one bad day doesn't stop
+/// the rest of the run.
+pub async fn run(trigger: Trigger) -> RetentionReport {
+    let _guard = RUNNING.lock().await;
+    STATUS.lock().unwrap().running = true;
+
+    let config = CONFIG.lock().unwrap().clone();
+    let mut report = RetentionReport {
+        trigger,
+        mode: config.mode.clone(),
+        started_at: Utc::now().to_rfc3339(),
+        finished_at: "".to_string(),
+        deleted: Vec::new(),
+        archived: Vec::new(),
+        pruned: Vec::new(),
+        errors: Vec::new(),
+    };
+
+    if let Err(error) = run_with(&config, &mut report).await {
+        report.errors.push(format!("{:#}", error));
+    }
+    report.finished_at = Utc::now().to_rfc3339();
+
+    for day in report.deleted.iter() {
+        println!("Retention: deleted {}", day);
+    }
+    for day in report.archived.iter() {
+        println!("Retention: archived {}", day);
+    }
+    for day in report.pruned.iter() {
+        println!("Retention: pruned {}", day);
+    }
+    for error in report.errors.iter() {
+        eprintln!("Retention: {}", error);
+    }
+
+    {
+        let mut status = STATUS.lock().unwrap();
+        status.running = false;
+        status.last_run = Some(report.clone());
+    }
+    report
+}
+
+async fn run_with(config: &Config, report: &mut RetentionReport) -> Result<()> {
+    let archive_path = Path::new(&config.archive_path);
+    let archive_store = ArchiveStore::from_config(config);
+    let today = Utc::now().date_naive();
+
+    let storage_path = Path::new(&config.storage_path);
+    for app_dir in read_dir(storage_path)? {
+        let app_path = app_dir?.path();
+        let app = app_path.file_name().unwrap().to_string_lossy().to_string();
+        if let Err(error) = idempotency::expire(&app_path, config.idempotency_window, Utc::now()) {
+            report.errors.push(format!(
+                "{}: failed to expire idempotency keys: {:#}",
+                app, error
+            ));
+        }
+
+        let log_path = app_path.join("ledgers");
+        if !log_path.exists() {
+            continue;
+        }
+
+        // Days younger than the shortest window that can apply to this app are
+        // kept as a whole, days older than the longest one are expired as a whole
+        // and anything in between is pruned entry by entry.
+        let windows: Vec<u32> = config
+            .retention
+            .iter()
+            .filter(|policy| policy.applies_to_app(&app))
+            .map(|policy| policy.days)
+            .chain([config.days_retained])
+            .collect();
+        let keep_date = days_ago(today, *windows.iter().min().unwrap());
+        let expire_date = days_ago(today, *windows.iter().max().unwrap());
+
+        for log in read_dir(log_path)? {
+            let log = log?.path();
+            let log_date = log.file_name().unwrap().to_string_lossy().to_string();
+            let log_date = log_date.replace(".jsonl", "");
+            let Ok(log_date) = NaiveDate::parse_from_str(log_date.as_str(), "%Y-%m-%d") else {
+                continue;
+            };
+            if log_date >= keep_date {
+                continue;
+            }
+
+            let date = log_date.format("%Y-%m-%d").to_string();
+            let day = format!("{}/{}", app, date);
+            if log_date >= expire_date {
+                let result = prune_day(
+                    config,
+                    &archive_store,
+                    &app_path,
+                    archive_path,
+                    &log,
+                    &date,
+                    today,
+                )
+                .await;
+                match result {
+                    Ok(0) => {}
+                    Ok(pruned) => report.pruned.push(format!("{} ({} entries)", day, pruned)),
+                    Err(error) => report.errors.push(format!("{}: {:#}", day, error)),
+                }
+                continue;
+            }
+
+            let result = expire_day(
+                config,
+                &archive_store,
+                &app_path,
+                archive_path,
+                &log,
+                &date,
+                expire_date,
+            )
+            .await;
+            match result {
+                Ok(Some(Rentention::DELETE)) => report.deleted.push(day),
+                Ok(Some(Rentention::ARCHIVE)) => report.archived.push(day),
+                Ok(None) => {}
+                Err(error) => report.errors.push(format!("{}: {:#}", day, error)),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn days_ago(today: NaiveDate, days: u32) -> NaiveDate {
+    today
+        .checked_sub_signed(chrono::Duration::days(days.into()))
+        .unwrap()
+}
+
+/// The fields retention policies match on. Everything else in the ledger line
+/// is carried over untouched.
+#[derive(Deserialize)]
+struct LedgerEntry {
+    uid: String,
+    level: ErrorLevel,
+    #[serde(default)]
+    env: String,
+}
+
+/// Removes the entries of a day whose retention policy has expired while
+/// keeping the rest. The ledger is rewritten under the ledger lock so entries
+/// appended in the meantime are never lost. In archive mode the expired entries
+/// are archived like a regular day and put back when archiving fails. Returns
+/// the number of entries removed.
+async fn prune_day(
+    config: &Config,
+    archive_store: &ArchiveStore,
+    app_path: &Path,
+    archive_path: &Path,
+    ledger: &Path,
+    date: &str,
+    today: NaiveDate,
+) -> Result<usize> {
+    // Restored days are kept whole until the longest window has passed.
+    if archive::restore_marker(app_path, date).is_some() {
+        return Ok(0);
+    }
+
+    let app = app_path.file_name().unwrap().to_string_lossy().to_string();
+    let day = date.parse::<NaiveDate>()?;
+    let search_path = app_path.join("search").join(date);
+    let staging_path = archive_path.join(".staging").join(&app);
+
+    let expired = {
+        let _ledger_lock = LEDGER_LOCK.lock().unwrap();
+        let contents = fs::read_to_string(ledger)?;
+        let mut kept = String::new();
+        let mut expired: Vec<(String, String)> = Vec::new();
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            // Unreadable lines are left for whole-day expiry.
+            let expired_uid = serde_json::from_str::<LedgerEntry>(line)
+                .ok()
+                .filter(|entry| {
+                    let days = config.retention_days(&app, entry.level, &entry.env);
+                    day < days_ago(today, days)
+                })
+                .map(|entry| entry.uid);
+            if let Some(uid) = expired_uid {
+                expired.push((uid, line.to_string()));
+            } else {
+                kept.push_str(line);
+                kept.push('\n');
+            }
+        }
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        if config.mode == Rentention::ARCHIVE {
+            stage_entries(&staging_path, &search_path, date, &expired)?;
+        }
+
+        let tmp = ledger.with_extension("jsonl.tmp");
+        fs::write(&tmp, kept)?;
+        fs::rename(&tmp, ledger)?;
+        let uids: Vec<String> = expired.iter().map(|(uid, _)| uid.clone()).collect();
+        search::delete(app_path, date, &uids)?;
+
+        if config.mode == Rentention::DELETE {
+            for (uid, _) in expired.iter() {
+                let _ = fs::remove_file(search_path.join(uid));
+            }
+        }
+        expired
+    };
+
+    if config.mode == Rentention::ARCHIVE {
+        let result = archive::archive_day(archive_store, &staging_path, archive_path, date).await;
+        if let Err(error) = result {
+            unstage_entries(&staging_path, app_path, ledger, date, &expired)
+                .context("Failed to put entries back after archiving failed")?;
+            return Err(error);
+        }
+        let _ = fs::remove_dir_all(&staging_path);
+    }
+
+    Ok(expired.len())
+}
+
+/// Moves expired entries into a staging app directory using the live storage
+/// layout so they can be archived with `archive::archive_day`.
+fn stage_entries(
+    staging_path: &Path,
+    search_path: &Path,
+    date: &str,
+    expired: &[(String, String)],
+) -> Result<()> {
+    let staged_search = staging_path.join("search").join(date);
+    fs::create_dir_all(staging_path.join("ledgers"))?;
+    fs::create_dir_all(&staged_search)?;
+
+    let mut staged_ledger =
+        fs::File::create(staging_path.join("ledgers").join(format!("{}.jsonl", date)))?;
+    for (uid, line) in expired {
+        writeln!(staged_ledger, "{}", line)?;
+        if search_path.join(uid).exists() {
+            fs::rename(search_path.join(uid), staged_search.join(uid))?;
+        }
+    }
+    staged_ledger.sync_all()?;
+    Ok(())
+}
+
+/// Returns staged entries to the live ledger, search cache and search index.
+fn unstage_entries(
+    staging_path: &Path,
+    app_path: &Path,
+    ledger: &Path,
+    date: &str,
+    expired: &[(String, String)],
+) -> Result<()> {
+    let _ledger_lock = LEDGER_LOCK.lock().unwrap();
+    let search_path = app_path.join("search").join(date);
+    let staged_search = staging_path.join("search").join(date);
+    let mut live_ledger = OpenOptions::new().append(true).open(ledger)?;
+    let mut logs: Vec<Log> = Vec::new();
+    for (uid, line) in expired {
+        writeln!(live_ledger, "{}", line)?;
+        if staged_search.join(uid).exists() {
+            fs::rename(staged_search.join(uid), search_path.join(uid))?;
+        }
+        if let Ok(log) = serde_json::from_str(line) {
+            logs.push(log);
+        }
+    }
+    search::add(app_path, date, &logs.iter().collect::<Vec<&Log>>())?;
+    if staging_path.exists() {
+        archive::remove_day(staging_path, date)?;
+    }
+    Ok(())
+}
+
+/// Applies the retention mode to a single expired day. Returns what was done, or
+/// `None` when the day was kept.
+async fn expire_day(
+    config: &Config,
+    archive_store: &ArchiveStore,
+    app_path: &Path,
+    archive_path: &Path,
+    ledger: &Path,
+    date: &str,
+    retention_date: NaiveDate,
+) -> Result<Option<Rentention>> {
+    if let Some(marker) = archive::restore_marker(app_path, date) {
+        // Restored days get a fresh retention window. Once it has passed, a
+        // ledger holding only the restored entries is already fully covered by
+        // its archives. Anything else is archived like any other day.
+        let restored_at = DateTime::parse_from_rfc3339(&marker.restored_at)?;
+        if restored_at.date_naive() >= retention_date {
+            return Ok(None);
+        }
+        // Locked so a log written in between can't be removed unarchived.
+        let _ledger_lock = LEDGER_LOCK.lock().unwrap();
+        if marker.only_restored && fs::metadata(ledger)?.len() == marker.ledger_size {
+            archive::remove_day(app_path, date)?;
+            return Ok(Some(Rentention::DELETE));
+        }
+    }
+
+    match config.mode {
+        Rentention::DELETE => {
+            let _ledger_lock = LEDGER_LOCK.lock().unwrap();
+            archive::remove_day(app_path, date)?;
+            Ok(Some(Rentention::DELETE))
+        }
+        Rentention::ARCHIVE => {
+            archive::archive_day(archive_store, app_path, archive_path, date).await?;
+            Ok(Some(Rentention::ARCHIVE))
+        }
+    }
+}
@@ -0,0 +1,668 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, read_dir, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tantivy::collector::{Count, TopDocs};
+use tantivy::directory::MmapDirectory;
+use tantivy::query::{BooleanQuery, BoostQuery, FuzzyTermQuery, Occur, Query, TermQuery};
+use tantivy::schema::{Field, IndexRecordOption, Schema, Value, STORED, STRING, TEXT};
+use tantivy::{Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument, Term};
+
+use crate::Log;
+
+/// Memory used by an index writer before it flushes, tantivy's minimum.
+const WRITER_MEMORY: usize = 15_000_000;
+
+/// How often logs queued by ingestion are committed, which is how long they
+/// can take to show up in searches.
+const COMMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Writers of days that got no logs for this long are closed.
+const WRITER_IDLE: Duration = Duration::from_secs(60);
+
+/// Bytes of a ledger read at a time when looking for its last indexed log.
+const READ_BLOCK: u64 = 64 * 1024;
+
+/// Full-text fields searched by a query, every other field of a log is only
+/// stored in the ledger.
+const TEXT_FIELDS: [&str; 5] = ["message", "file", "function", "category", "custom"];
+
+/// Exact word matches rank above words that merely start with a query term.
+const EXACT_BOOST: f32 = 2.0;
+
+static INDEXER: OnceLock<Indexer> = OnceLock::new();
+
+/// Every day of an app has its own index in `<app>/index/<date>`, next to its
+/// ledger, so retention can drop it along with the day.
+pub fn index_path(app_path: &Path, date: &str) -> PathBuf {
+    app_path.join("index").join(date)
+}
+
+fn schema() -> Schema {
+    let mut schema = Schema::builder();
+    schema.add_text_field("uid", STRING | STORED);
+    for field in TEXT_FIELDS {
+        schema.add_text_field(field, TEXT);
+    }
+    schema.build()
+}
+
+fn open(app_path: &Path, date: &str) -> Result<Index> {
+    let path = index_path(app_path, date);
+    fs::create_dir_all(&path)?;
+    let index = Index::open_or_create(MmapDirectory::open(&path)?, schema())
+        .with_context(|| format!("Failed to open search index {}", path.display()))?;
+    Ok(index)
+}
+
+/// Whether a day's index has been created, which only the indexer does.
+fn exists(app_path: &Path, date: &str) -> bool {
+    MmapDirectory::open(index_path(app_path, date))
+        .ok()
+        .and_then(|directory| Index::exists(&directory).ok())
+        .unwrap_or(false)
+}
+
+fn field(index: &Index, name: &str) -> Field {
+    index.schema().get_field(name).unwrap()
+}
+
+fn reader(index: &Index) -> Result<IndexReader> {
+    Ok(index
+        .reader_builder()
+        .reload_policy(ReloadPolicy::Manual)
+        .try_into()?)
+}
+
+fn document(index: &Index, log: &Log) -> TantivyDocument {
+    let mut custom: Vec<String> = log
+        .custom
+        .iter()
+        .map(|(key, value)| format!("{} {}", key, value))
+        .collect();
+    custom.sort();
+
+    let mut document = TantivyDocument::default();
+    document.add_text(field(index, "uid"), &log.uid);
+    document.add_text(field(index, "message"), &log.message);
+    document.add_text(field(index, "file"), &log.file);
+    document.add_text(field(index, "function"), &log.function);
+    document.add_text(field(index, "category"), &log.category);
+    document.add_text(field(index, "custom"), custom.join("\n"));
+    document
+}
+
+struct Indexer {
+    sender: mpsc::Sender<Message>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+enum Message {
+    Command(Command),
+    /// A change that waits until the indexer is otherwise idle.
+    Background(Command),
+    /// Commits every writer and ends the thread.
+    Stop,
+}
+
+enum Change {
+    Add(Vec<Log>),
+    Delete(Vec<String>),
+    Remove,
+    /// Indexes the logs at the end of the ledger that aren't in the index.
+    Reconcile,
+}
+
+struct Command {
+    app_path: PathBuf,
+    date: String,
+    change: Change,
+    /// Set when the caller waits for the change to be committed.
+    done: Option<mpsc::Sender<Result<()>>>,
+}
+
+struct OpenWriter {
+    index: Index,
+    writer: IndexWriter,
+    pending: bool,
+    changed: Instant,
+}
+
+/// Every index change goes through a single thread, which keeps a writer open
+/// per day being written and commits them together, so ingestion never waits
+/// on the index.
+fn indexer() -> &'static Indexer {
+    INDEXER.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("indexer".to_string())
+            .spawn(move || run(receiver))
+            .expect("Failed to start the indexer");
+        Indexer {
+            sender,
+            thread: Mutex::new(Some(thread)),
+        }
+    })
+}
+
+fn run(receiver: mpsc::Receiver<Message>) {
+    let mut writers: HashMap<PathBuf, OpenWriter> = HashMap::new();
+    // Days caught up with their ledger since the indexer started.
+    let mut reconciled: HashSet<PathBuf> = HashSet::new();
+    // Days reconciled on startup, whenever nothing else is waiting.
+    let mut backlog: VecDeque<Command> = VecDeque::new();
+    let mut committed = Instant::now();
+    loop {
+        let timeout = match backlog.is_empty() {
+            true => COMMIT_INTERVAL.saturating_sub(committed.elapsed()),
+            false => Duration::ZERO,
+        };
+        match receiver.recv_timeout(timeout) {
+            Ok(Message::Command(command)) => execute(&mut writers, &mut reconciled, command),
+            Ok(Message::Background(command)) => backlog.push_back(command),
+            Ok(Message::Stop) | Err(RecvTimeoutError::Disconnected) => {
+                commit(&mut writers);
+                return;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if let Some(command) = backlog.pop_front() {
+                    let path = index_path(&command.app_path, &command.date);
+                    if !reconciled.contains(&path) {
+                        execute(&mut writers, &mut reconciled, command);
+                    }
+                }
+            }
+        }
+        if committed.elapsed() >= COMMIT_INTERVAL {
+            commit(&mut writers);
+            committed = Instant::now();
+        }
+    }
+}
+
+fn execute(
+    writers: &mut HashMap<PathBuf, OpenWriter>,
+    reconciled: &mut HashSet<PathBuf>,
+    command: Command,
+) {
+    let path = index_path(&command.app_path, &command.date);
+    let result = apply(writers, reconciled, &command);
+    if result.is_err() {
+        writers.remove(&path);
+        reconciled.remove(&path);
+    }
+    match command.done {
+        Some(done) => {
+            let _ = done.send(result);
+        }
+        None => {
+            if let Err(error) = result {
+                eprintln!("Failed to index {}: {:#}", path.display(), error);
+            }
+        }
+    }
+}
+
+/// Applies a change to the writer of its day. Changes somebody waits on, and
+/// reconciled days, are committed right away and their writer closed.
+fn apply(
+    writers: &mut HashMap<PathBuf, OpenWriter>,
+    reconciled: &mut HashSet<PathBuf>,
+    command: &Command,
+) -> Result<()> {
+    let path = index_path(&command.app_path, &command.date);
+    let reconcile = match &command.change {
+        Change::Remove => {
+            writers.remove(&path);
+            reconciled.remove(&path);
+            if path.exists() {
+                fs::remove_dir_all(&path)?;
+            }
+            return Ok(());
+        }
+        Change::Delete(_) if !writers.contains_key(&path) && !path.exists() => return Ok(()),
+        Change::Reconcile => true,
+        _ => false,
+    };
+    // Logs the index missed before the last stop go in before anything newer,
+    // so the index keeps holding every log up to its last one.
+    let mut logs = Vec::new();
+    if reconciled.insert(path.clone()) || reconcile {
+        logs = unindexed(writers, &command.app_path, &command.date)?;
+    }
+    if reconcile && logs.is_empty() {
+        return Ok(());
+    }
+    if !writers.contains_key(&path) {
+        let index = open(&command.app_path, &command.date)?;
+        let writer = index.writer_with_num_threads(1, WRITER_MEMORY)?;
+        writers.insert(
+            path.clone(),
+            OpenWriter {
+                index,
+                writer,
+                pending: false,
+                changed: Instant::now(),
+            },
+        );
+    }
+    let open = writers.get_mut(&path).unwrap();
+    if let Change::Add(added) = &command.change {
+        logs.extend(added.iter().cloned());
+    }
+    for log in &logs {
+        // Replaces the log if it's already indexed, so a log queued while its
+        // day is reconciled isn't indexed twice.
+        let uid = Term::from_field_text(field(&open.index, "uid"), &log.uid);
+        open.writer.delete_term(uid);
+        open.writer.add_document(document(&open.index, log))?;
+    }
+    if let Change::Delete(uids) = &command.change {
+        for uid in uids {
+            open.writer
+                .delete_term(Term::from_field_text(field(&open.index, "uid"), uid));
+        }
+    }
+    open.pending = true;
+    open.changed = Instant::now();
+    if command.done.is_some() || reconcile {
+        let mut open = writers.remove(&path).unwrap();
+        open.writer.commit()?;
+    }
+    Ok(())
+}
+
+/// Finds the logs at the end of a day's ledger that are missing from its
+/// index. Changes are indexed in the order they were written to the ledger,
+/// so everything before the last indexed log is indexed too. Creates the
+/// index of a day that has none.
+fn unindexed(
+    writers: &mut HashMap<PathBuf, OpenWriter>,
+    app_path: &Path,
+    date: &str,
+) -> Result<Vec<Log>> {
+    let ledger = app_path.join("ledgers").join(format!("{}.jsonl", date));
+    if !ledger.exists() {
+        return Ok(Vec::new());
+    }
+    let index = match writers.get_mut(&index_path(app_path, date)) {
+        Some(open) => {
+            if open.pending {
+                open.writer.commit()?;
+                open.pending = false;
+            }
+            open.index.clone()
+        }
+        None => open(app_path, date)?,
+    };
+    let searcher = reader(&index)?.searcher();
+    let uid_field = field(&index, "uid");
+    last_logs(&ledger, |uid| {
+        let uid = TermQuery::new(
+            Term::from_field_text(uid_field, uid),
+            IndexRecordOption::Basic,
+        );
+        Ok(searcher.search(&uid, &Count)? > 0)
+    })
+}
+
+/// Reads a ledger backwards until `is_indexed` holds for a log, returning the
+/// logs after it, oldest first. The last line is skipped when it is still
+/// being written.
+fn last_logs(ledger: &Path, is_indexed: impl Fn(&str) -> Result<bool>) -> Result<Vec<Log>> {
+    let mut file = match File::open(ledger) {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error.into()),
+    };
+    let mut end = file.metadata()?.len();
+    // The start of the line that continues in the block read before.
+    let mut rest = Vec::new();
+    let mut complete = false;
+    let mut logs = Vec::new();
+    while end > 0 {
+        let start = end.saturating_sub(READ_BLOCK);
+        let mut block = vec![0u8; (end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut block)?;
+        block.extend_from_slice(&rest);
+        end = start;
+        if !complete {
+            match block.iter().rposition(|&byte| byte == b'\n') {
+                Some(position) => block.truncate(position + 1),
+                None => {
+                    rest.clear();
+                    continue;
+                }
+            }
+            complete = true;
+        }
+        let lines_start = match block.iter().position(|&byte| byte == b'\n') {
+            _ if start == 0 => 0,
+            Some(position) => position + 1,
+            None => {
+                rest = block;
+                continue;
+            }
+        };
+        for line in block[lines_start..].split(|&byte| byte == b'\n').rev() {
+            let Ok(log) = serde_json::from_slice::<Log>(line) else {
+                continue;
+            };
+            if is_indexed(&log.uid)? {
+                logs.reverse();
+                return Ok(logs);
+            }
+            logs.push(log);
+        }
+        block.truncate(lines_start);
+        rest = block;
+    }
+    logs.reverse();
+    Ok(logs)
+}
+
+/// Commits the writers with new changes, and closes those left idle.
+fn commit(writers: &mut HashMap<PathBuf, OpenWriter>) {
+    writers.retain(|path, open| {
+        if open.pending {
+            if let Err(error) = open.writer.commit() {
+                eprintln!("Failed to index {}: {:#}", path.display(), error);
+                return false;
+            }
+            open.pending = false;
+        }
+        open.changed.elapsed() < WRITER_IDLE
+    });
+}
+
+fn send(app_path: &Path, date: &str, change: Change, wait: bool) -> Result<()> {
+    let (done, result) = mpsc::channel();
+    indexer()
+        .sender
+        .send(Message::Command(Command {
+            app_path: app_path.to_path_buf(),
+            date: date.to_string(),
+            change,
+            done: wait.then_some(done),
+        }))
+        .map_err(|_| anyhow!("The indexer stopped"))?;
+    if !wait {
+        return Ok(());
+    }
+    result.recv().map_err(|_| anyhow!("The indexer stopped"))?
+}
+
+/// Queues logs of a single day to be indexed with the next commit, without
+/// waiting for it. Called with the ledger lock held, so logs are queued in the
+/// order they are written. A day without an index is indexed from its ledger.
+pub fn queue(app_path: &Path, date: &str, logs: Vec<Log>) -> Result<()> {
+    if logs.is_empty() {
+        return Ok(());
+    }
+    let change = match exists(app_path, date) {
+        true => Change::Add(logs),
+        false => Change::Reconcile,
+    };
+    send(app_path, date, change, false)
+}
+
+/// Indexes logs of a single day, and waits for them to be committed.
+pub fn add(app_path: &Path, date: &str, logs: &[&Log]) -> Result<()> {
+    if logs.is_empty() {
+        return Ok(());
+    }
+    let logs = logs.iter().map(|&log| log.clone()).collect();
+    send(app_path, date, Change::Add(logs), true)
+}
+
+/// Removes logs from a day's index, and waits for it to be committed.
+pub fn delete(app_path: &Path, date: &str, uids: &[String]) -> Result<()> {
+    if uids.is_empty() {
+        return Ok(());
+    }
+    send(app_path, date, Change::Delete(uids.to_vec()), true)
+}
+
+/// Removes a day's index, closing its writer first.
+pub fn remove(app_path: &Path, date: &str) -> Result<()> {
+    send(app_path, date, Change::Remove, true)
+}
+
+/// Indexes the logs of a day's ledger that are missing from its index, and
+/// waits for them to be committed. Days written before the index existed are
+/// indexed in full.
+pub fn reconcile(app_path: &Path, date: &str) -> Result<()> {
+    send(app_path, date, Change::Reconcile, true)
+}
+
+/// Queues every day of every app to be reconciled, which indexes the logs
+/// that were written but not yet committed when Lumberjack last stopped, and
+/// days written by older versions.
+pub fn reconcile_all(storage_path: &Path) -> Result<()> {
+    if !storage_path.exists() {
+        return Ok(());
+    }
+    for app in read_dir(storage_path)? {
+        let app_path = app?.path();
+        let Ok(ledgers) = read_dir(app_path.join("ledgers")) else {
+            continue;
+        };
+        for ledger in ledgers {
+            let name = ledger?.file_name().to_string_lossy().to_string();
+            let Some(date) = name.strip_suffix(".jsonl") else {
+                continue;
+            };
+            indexer()
+                .sender
+                .send(Message::Background(Command {
+                    app_path: app_path.clone(),
+                    date: date.to_string(),
+                    change: Change::Reconcile,
+                    done: None,
+                }))
+                .map_err(|_| anyhow!("The indexer stopped"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Commits every pending change and stops the indexer.
+pub fn shutdown() {
+    let Some(indexer) = INDEXER.get() else {
+        return;
+    };
+    let _ = indexer.sender.send(Message::Stop);
+    if let Some(thread) = indexer.thread.lock().unwrap().take() {
+        let _ = thread.join();
+    }
+}
+
+/// Finds the uids of a day's logs matching every word of the query, best
+/// matches first. A word matches a field containing it, or a word starting
+/// with it.
+pub fn search(app_path: &Path, date: &str, query: &str) -> Result<Vec<String>> {
+    if !exists(app_path, date) {
+        reconcile(app_path, date)?;
+        if !exists(app_path, date) {
+            return Ok(Vec::new());
+        }
+    }
+    let index = Index::open_in_dir(index_path(app_path, date))?;
+
+    let mut words = Vec::new();
+    let mut tokenizer = index.tokenizer_for_field(field(&index, "message"))?;
+    let mut stream = tokenizer.token_stream(query);
+    while let Some(token) = stream.next() {
+        words.push(token.text.clone());
+    }
+    if words.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let clauses: Vec<(Occur, Box<dyn Query>)> = words
+        .iter()
+        .map(|word| {
+            let mut matches: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+            for name in TEXT_FIELDS {
+                let term = Term::from_field_text(field(&index, name), word);
+                let exact = TermQuery::new(term.clone(), IndexRecordOption::WithFreqs);
+                matches.push((
+                    Occur::Should,
+                    Box::new(BoostQuery::new(Box::new(exact), EXACT_BOOST)),
+                ));
+                matches.push((
+                    Occur::Should,
+                    Box::new(FuzzyTermQuery::new_prefix(term, 0, true)),
+                ));
+            }
+            let word: Box<dyn Query> = Box::new(BooleanQuery::new(matches));
+            (Occur::Must, word)
+        })
+        .collect();
+    let query = BooleanQuery::new(clauses);
+
+    let searcher = index.reader()?.searcher();
+    let limit = (searcher.num_docs() as usize).max(1);
+    let uid_field = field(&index, "uid");
+    let mut uids = Vec::new();
+    for (_, address) in searcher.search(&query, &TopDocs::with_limit(limit))? {
+        let document: TantivyDocument = searcher.doc(address)?;
+        if let Some(uid) = document.get_first(uid_field).and_then(|uid| uid.as_str()) {
+            uids.push(uid.to_string());
+        }
+    }
+    Ok(uids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::empty_log;
+    use std::io::Write;
+
+    fn log(message: &str, file: &str, custom: &[(&str, &str)]) -> Log {
+        let mut log = empty_log();
+        log.message = message.to_string();
+        log.file = file.to_string();
+        for (key, value) in custom {
+            log.custom.insert(key.to_string(), value.to_string());
+        }
+        log
+    }
+
+    #[test]
+    fn finds_words_and_prefixes_best_first() {
+        let app_path =
+            std::env::temp_dir().join(format!("lumberjack-search-{}", uuid::Uuid::now_v7()));
+        let timeout = log("Database timeout after 30s", "db.rs", &[]);
+        let timed = log("Request timed out", "http.rs", &[("user_id", "42")]);
+        let payment = log("Payment declined", "pay.rs", &[("user_id", "7")]);
+        add(&app_path, "2024-04-06", &[&timeout, &timed, &payment]).unwrap();
+
+        let search = |query| search(&app_path, "2024-04-06", query).unwrap();
+        assert_eq!(search("timeout"), vec![timeout.uid.clone()]);
+        let mut uids = search("TIME");
+        uids.sort();
+        let mut expected = vec![timeout.uid.clone(), timed.uid.clone()];
+        expected.sort();
+        assert_eq!(uids, expected);
+        assert_eq!(search("timed"), vec![timed.uid.clone()]);
+        assert_eq!(search("pay.rs"), vec![payment.uid.clone()]);
+        assert_eq!(search("user_id 42"), vec![timed.uid.clone()]);
+        assert!(search("(.*)*$").is_empty());
+        assert!(search("missing").is_empty());
+
+        delete(&app_path, "2024-04-06", std::slice::from_ref(&timed.uid)).unwrap();
+        assert!(search("timed").is_empty());
+
+        fs::remove_dir_all(&app_path).unwrap();
+    }
+
+    #[test]
+    fn indexes_days_written_before_the_index() {
+        let app_path =
+            std::env::temp_dir().join(format!("lumberjack-search-{}", uuid::Uuid::now_v7()));
+        let old = log("Written by an older version", "", &[]);
+        fs::create_dir_all(app_path.join("ledgers")).unwrap();
+        fs::write(
+            app_path.join("ledgers").join("2024-04-06.jsonl"),
+            serde_json::to_string(&old).unwrap() + "\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            search(&app_path, "2024-04-06", "older").unwrap(),
+            vec![old.uid.clone()]
+        );
+        assert!(index_path(&app_path, "2024-04-06").exists());
+
+        fs::remove_dir_all(&app_path).unwrap();
+    }
+
+    #[test]
+    fn ranks_exact_words_above_prefixes() {
+        let app_path =
+            std::env::temp_dir().join(format!("lumberjack-search-{}", uuid::Uuid::now_v7()));
+        let prefix = log("Connection errored twice", "", &[]);
+        let exact = log("Connection error", "", &[]);
+        add(&app_path, "2024-04-06", &[&prefix, &exact]).unwrap();
+
+        let uids = search(&app_path, "2024-04-06", "error").unwrap();
+        assert_eq!(uids, vec![exact.uid.clone(), prefix.uid.clone()]);
+
+        fs::remove_dir_all(&app_path).unwrap();
+    }
+
+    #[test]
+    fn indexes_logs_lost_before_a_commit() {
+        let app_path =
+            std::env::temp_dir().join(format!("lumberjack-search-{}", uuid::Uuid::now_v7()));
+        let date = "2024-04-06";
+        // Long enough to be read back in several blocks.
+        let logs: Vec<Log> = (0..2000)
+            .map(|i| log(&format!("entry{}", i), "", &[]))
+            .collect();
+        let ledger = |logs: &[Log]| {
+            let mut ledger = String::new();
+            for log in logs {
+                ledger.push_str(&serde_json::to_string(log).unwrap());
+                ledger.push('\n');
+            }
+            ledger
+        };
+        let path = app_path.join("ledgers").join("2024-04-06.jsonl");
+        fs::create_dir_all(app_path.join("ledgers")).unwrap();
+        fs::write(&path, ledger(&logs[..1000])).unwrap();
+        add(&app_path, date, &logs[..1000].iter().collect::<Vec<&Log>>()).unwrap();
+
+        // The rest was written, and a log is still being written, but the
+        // indexer stopped before committing them.
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(ledger(&logs[1000..]).as_bytes()).unwrap();
+        file.write_all(b"{\"uid\":").unwrap();
+        let index = open(&app_path, date).unwrap();
+        let writer: IndexWriter = index.writer_with_num_threads(1, WRITER_MEMORY).unwrap();
+        for log in &logs[1000..] {
+            writer.add_document(document(&index, log)).unwrap();
+        }
+        drop(writer);
+        assert!(search(&app_path, date, "entry1999").unwrap().is_empty());
+
+        reconcile(&app_path, date).unwrap();
+        for i in [0, 999, 1000, 1999] {
+            let query = format!("entry{}", i);
+            assert_eq!(
+                search(&app_path, date, &query).unwrap(),
+                vec![logs[i].uid.clone()]
+            );
+        }
+
+        fs::remove_dir_all(&app_path).unwrap();
+    }
+}
@@ -0,0 +1,434 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Datelike, NaiveDateTime, SecondsFormat, Utc};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+use crate::config::{Config, Listener, ListenerProtocol};
+use crate::ingest::empty_log;
+use crate::{to_kebab_case, write_log_files, ErrorLevel, Log};
+
+const FACILITIES: [&str; 24] = [
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv",
+    "ftp", "ntp", "audit", "alert", "clock", "local0", "local1", "local2", "local3", "local4",
+    "local5", "local6", "local7",
+];
+
+/// Binds every configured syslog listener and spawns the tasks serving them.
+pub async fn start(config: &Config) -> Result<()> {
+    let storage_path = PathBuf::from(&config.storage_path);
+    for listener in config.syslog.iter() {
+        let storage_path = storage_path.clone();
+        let max_message_size = config.max_entry_size;
+        match listener.protocol {
+            ListenerProtocol::Udp => {
+                let socket = UdpSocket::bind(&listener.bind)
+                    .await
+                    .with_context(|| format!("Failed to bind syslog UDP {}", listener.bind))?;
+                tokio::spawn(serve_udp(socket, listener.clone(), storage_path));
+            }
+            ListenerProtocol::Tcp => {
+                let socket = TcpListener::bind(&listener.bind)
+                    .await
+                    .with_context(|| format!("Failed to bind syslog TCP {}", listener.bind))?;
+                tokio::spawn(serve_tcp(
+                    socket,
+                    listener.clone(),
+                    storage_path,
+                    max_message_size,
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn serve_udp(socket: UdpSocket, listener: Listener, storage_path: PathBuf) {
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        match socket.recv_from(&mut buffer).await {
+            Ok((size, _)) => {
+                let message = String::from_utf8_lossy(&buffer[..size]);
+                handle(&listener, &storage_path, &message);
+            }
+            Err(error) => eprintln!("Syslog UDP {}: {}", listener.bind, error),
+        }
+    }
+}
+
+async fn serve_tcp(
+    socket: TcpListener,
+    listener: Listener,
+    storage_path: PathBuf,
+    max_message_size: usize,
+) {
+    loop {
+        let stream = match socket.accept().await {
+            Ok((stream, _)) => stream,
+            Err(error) => {
+                eprintln!("Syslog TCP {}: {}", listener.bind, error);
+                continue;
+            }
+        };
+        let listener = listener.clone();
+        let storage_path = storage_path.clone();
+        tokio::spawn(async move {
+            let mut reader = BufReader::new(stream);
+            loop {
+                match read_frame(&mut reader, max_message_size).await {
+                    Ok(Some(frame)) => {
+                        let message = String::from_utf8_lossy(&frame);
+                        handle(&listener, &storage_path, &message);
+                    }
+                    Ok(None) => break,
+                    Err(error) => {
+                        eprintln!("Syslog TCP {}: {:#}", listener.bind, error);
+                        break;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Reads the next message of a TCP stream framed with either octet counting
+/// (`<length> <message>`) or a trailing newline, as described by RFC 6587.
+async fn read_frame(
+    reader: &mut BufReader<TcpStream>,
+    max_message_size: usize,
+) -> Result<Option<Vec<u8>>> {
+    loop {
+        let buffer = reader.fill_buf().await?;
+        if buffer.is_empty() {
+            return Ok(None);
+        }
+
+        if buffer[0].is_ascii_digit() {
+            let mut length = Vec::new();
+            (&mut *reader)
+                .take(11)
+                .read_until(b' ', &mut length)
+                .await?;
+            let length: usize = std::str::from_utf8(&length)?
+                .trim()
+                .parse()
+                .context("Invalid octet count")?;
+            if length > max_message_size {
+                return Err(anyhow!(
+                    "Message of {} bytes exceeds the maximum of {}",
+                    length,
+                    max_message_size
+                ));
+            }
+            let mut frame = vec![0u8; length];
+            reader.read_exact(&mut frame).await?;
+            return Ok(Some(frame));
+        }
+
+        let mut frame = Vec::new();
+        (&mut *reader)
+            .take(max_message_size as u64 + 1)
+            .read_until(b'\n', &mut frame)
+            .await?;
+        if frame.len() > max_message_size {
+            return Err(anyhow!(
+                "Message exceeds the maximum of {} bytes",
+                max_message_size
+            ));
+        }
+        if !frame.iter().all(|byte| byte.is_ascii_whitespace()) {
+            return Ok(Some(frame));
+        }
+    }
+}
+
+/// Parses a message and writes it to its app when the listener allows it.
+fn handle(listener: &Listener, storage_path: &Path, message: &str) {
+    let message = message.trim_end_matches(['\r', '\n', '\0']);
+    if message.trim().is_empty() {
+        return;
+    }
+    let (app_name, mut log) = parse(message);
+    if let (Some(_), Some(app_name)) = (&listener.app, &app_name) {
+        log.custom.insert("app_name".to_string(), app_name.clone());
+    }
+    let Some(app) = listener
+        .app
+        .clone()
+        .or(app_name.map(|app| to_kebab_case(&app)))
+        .filter(|app| !app.is_empty())
+    else {
+        eprintln!(
+            "Syslog {}: dropping message without an app name",
+            listener.bind
+        );
+        return;
+    };
+    if !listener.allows(&app) {
+        eprintln!(
+            "Syslog {}: dropping message for unauthorized app {}",
+            listener.bind, app
+        );
+        return;
+    }
+
+    log.env = listener.env.clone();
+    if let Err(error) = write_log_files(vec![log], storage_path.join(&app)) {
+        eprintln!("Syslog {}: failed to write log: {:#}", listener.bind, error);
+    }
+}
+
+/// Parses an RFC 5424 or RFC 3164 message into a log, along with the app named
+/// by its APP-NAME or TAG. Messages that follow neither format are kept whole
+/// as the log message.
+pub fn parse(message: &str) -> (Option<String>, Log) {
+    let mut log = empty_log();
+    // RFC 3164 treats messages without a PRI as user.notice.
+    log.level = ErrorLevel::Notice;
+
+    let mut rest = message;
+    if let Some((pri, after)) = parse_pri(message) {
+        log.level = ErrorLevel::from_alias(&(pri % 8).to_string()).unwrap_or(ErrorLevel::Unknown);
+        log.custom.insert(
+            "facility".to_string(),
+            FACILITIES[(pri / 8) as usize].to_string(),
+        );
+        rest = after;
+    }
+
+    let app = match rest.strip_prefix("1 ") {
+        Some(rest) => parse_rfc5424(rest, &mut log),
+        None => parse_rfc3164(rest, &mut log),
+    };
+    if log.timestamp.is_empty() {
+        log.timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+    }
+    (app, log)
+}
+
+/// Splits `<PRI>` off the start of a message.
+fn parse_pri(message: &str) -> Option<(u8, &str)> {
+    let rest = message.strip_prefix('<')?;
+    let (pri, rest) = rest.split_once('>')?;
+    if pri.is_empty() || pri.len() > 3 || !pri.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let pri: u8 = pri.parse().ok()?;
+    if pri > 191 {
+        return None;
+    }
+    Some((pri, rest))
+}
+
+/// `TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA [MSG]`, where `-`
+/// marks a missing value.
+fn parse_rfc5424(message: &str, log: &mut Log) -> Option<String> {
+    let mut fields = message.splitn(6, ' ');
+    let timestamp = fields.next().unwrap_or("-");
+    let hostname = fields.next().unwrap_or("-");
+    let app = fields.next().unwrap_or("-");
+    let procid = fields.next().unwrap_or("-");
+    let msgid = fields.next().unwrap_or("-");
+    let rest = fields.next().unwrap_or("-");
+
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(timestamp) {
+        log.timestamp = timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true);
+    }
+    for (name, value) in [("hostname", hostname), ("procid", procid), ("msgid", msgid)] {
+        if value != "-" {
+            log.custom.insert(name.to_string(), value.to_string());
+        }
+    }
+
+    let message = match rest.strip_prefix('-') {
+        Some(message) => message,
+        None => match parse_structured_data(rest) {
+            Some((params, message)) => {
+                log.custom.extend(params);
+                message
+            }
+            None => rest,
+        },
+    };
+    log.message = message
+        .strip_prefix(' ')
+        .unwrap_or(message)
+        .trim_start_matches('\u{feff}')
+        .trim_end()
+        .to_string();
+
+    (app != "-").then(|| app.to_string())
+}
+
+/// Parses `[id name="value" ...]...` into `id.name` custom fields, returning
+/// them with the rest of the message. `None` when the elements are malformed.
+fn parse_structured_data(input: &str) -> Option<(HashMap<String, String>, &str)> {
+    let mut params = HashMap::new();
+    let mut rest = input;
+    while let Some(element) = rest.strip_prefix('[') {
+        let id_end = element.find([' ', ']'])?;
+        let id = &element[..id_end];
+        rest = &element[id_end..];
+        loop {
+            rest = rest.trim_start_matches(' ');
+            if let Some(after) = rest.strip_prefix(']') {
+                rest = after;
+                break;
+            }
+            let (name, after) = rest.split_once("=\"")?;
+            let mut value = String::new();
+            let mut chars = after.char_indices();
+            let end = loop {
+                match chars.next()? {
+                    (_, '\\') => {
+                        let (_, escaped) = chars.next()?;
+                        if !matches!(escaped, '"' | '\\' | ']') {
+                            value.push('\\');
+                        }
+                        value.push(escaped);
+                    }
+                    (i, '"') => break i,
+                    (_, c) => value.push(c),
+                }
+            };
+            params.insert(format!("{}.{}", id, name), value);
+            rest = &after[end + 1..];
+        }
+    }
+    Some((params, rest))
+}
+
+/// `TIMESTAMP HOSTNAME TAG: MSG` where TIMESTAMP is `Mmm dd hh:mm:ss` in an
+/// unknown timezone (UTC is assumed) or, as some relays send it, RFC 3339.
+fn parse_rfc3164(message: &str, log: &mut Log) -> Option<String> {
+    let mut rest = message;
+    if let Some(timestamp) = message.get(..15).and_then(parse_bsd_timestamp) {
+        log.timestamp = timestamp;
+        rest = message[15..].trim_start_matches(' ');
+    } else if let Some((first, after)) = message.split_once(' ') {
+        if let Ok(timestamp) = DateTime::parse_from_rfc3339(first) {
+            log.timestamp = timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true);
+            rest = after;
+        }
+    }
+
+    // The hostname is optional, a first word that already looks like a tag is
+    // the tag.
+    if !log.timestamp.is_empty() {
+        if let Some((hostname, after)) = rest.split_once(' ') {
+            if !hostname.is_empty() && parse_tag(hostname).is_none() {
+                log.custom
+                    .insert("hostname".to_string(), hostname.to_string());
+                rest = after;
+            }
+        }
+    }
+
+    let mut app = None;
+    if let Some((tag, after)) = rest.split_once(' ') {
+        if let Some((name, pid)) = parse_tag(tag) {
+            app = Some(name.to_string());
+            if let Some(pid) = pid {
+                log.custom.insert("procid".to_string(), pid.to_string());
+            }
+            rest = after;
+        }
+    }
+    log.message = rest.trim().to_string();
+    app
+}
+
+/// Parses a `name:` or `name[pid]:` tag.
+fn parse_tag(tag: &str) -> Option<(&str, Option<&str>)> {
+    let tag = tag.strip_suffix(':')?;
+    let (name, pid) = match tag.split_once('[') {
+        Some((name, pid)) => (name, Some(pid.strip_suffix(']')?)),
+        None => (tag, None),
+    };
+    if name.is_empty() || name.len() > 48 {
+        return None;
+    }
+    Some((name, pid))
+}
+
+/// Parses `Mmm dd hh:mm:ss`, picking the year that puts it closest to now.
+fn parse_bsd_timestamp(timestamp: &str) -> Option<String> {
+    let now = Utc::now();
+    let parse = |year: i32| {
+        NaiveDateTime::parse_from_str(&format!("{} {}", year, timestamp), "%Y %b %e %H:%M:%S")
+            .ok()
+            .map(|timestamp| timestamp.and_utc())
+    };
+    let mut parsed = parse(now.year())?;
+    if parsed > now + chrono::Duration::days(1) {
+        parsed = parse(now.year() - 1)?;
+    }
+    Some(parsed.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rfc5424_messages() {
+        let (app, log) = parse(
+            "<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 \
+             [exampleSDID@32473 iut=\"3\" eventSource=\"Application\" eventID=\"1011\"] \
+             \u{feff}An application event log entry...",
+        );
+        assert_eq!(app.as_deref(), Some("evntslog"));
+        assert_eq!(log.level, ErrorLevel::Notice);
+        assert_eq!(log.timestamp, "2003-10-11T22:14:15.003Z");
+        assert_eq!(log.message, "An application event log entry...");
+        assert_eq!(log.custom["hostname"], "mymachine.example.com");
+        assert_eq!(log.custom["msgid"], "ID47");
+        assert_eq!(log.custom["facility"], "local4");
+        assert_eq!(log.custom["exampleSDID@32473.eventSource"], "Application");
+        assert!(!log.custom.contains_key("procid"));
+    }
+
+    #[test]
+    fn parses_structured_data_escapes() {
+        let (_, log) =
+            parse("<11>1 2024-04-06T08:48:24Z host app 42 - [a x=\"q\\\"uo\\]te\"][b y=\"\"]");
+        assert_eq!(log.level, ErrorLevel::Error);
+        assert_eq!(log.custom["a.x"], "q\"uo]te");
+        assert_eq!(log.custom["b.y"], "");
+        assert_eq!(log.custom["procid"], "42");
+        assert_eq!(log.message, "");
+    }
+
+    #[test]
+    fn parses_rfc3164_messages() {
+        let (app, log) = parse(
+            "<34>Oct 11 22:14:15 mymachine su[123]: 'su root' failed for lonvick on /dev/pts/8",
+        );
+        assert_eq!(app.as_deref(), Some("su"));
+        assert_eq!(log.level, ErrorLevel::Critical);
+        assert!(log.timestamp.contains("-10-11T22:14:15"));
+        assert_eq!(log.custom["hostname"], "mymachine");
+        assert_eq!(log.custom["procid"], "123");
+        assert_eq!(log.custom["facility"], "auth");
+        assert_eq!(log.message, "'su root' failed for lonvick on /dev/pts/8");
+    }
+
+    #[test]
+    fn parses_rfc3164_without_hostname() {
+        let (app, log) = parse("<13>Apr  6 08:48:24 billing: Invoice 42 sent");
+        assert_eq!(app.as_deref(), Some("billing"));
+        assert_eq!(log.level, ErrorLevel::Notice);
+        assert!(!log.custom.contains_key("hostname"));
+        assert_eq!(log.message, "Invoice 42 sent");
+    }
+
+    #[test]
+    fn keeps_unstructured_messages_whole() {
+        let (app, log) = parse("something happened");
+        assert_eq!(app, None);
+        assert_eq!(log.level, ErrorLevel::Notice);
+        assert_eq!(log.message, "something happened");
+        assert!(!log.timestamp.is_empty());
+    }
+}
@@ -0,0 +1,164 @@
+use axum::extract::ws::{Message, WebSocket};
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use futures_util::stream::{self, Stream};
+use serde_json::json;
+use std::convert::Infallible;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::ledger::Filter;
+use crate::Log;
+
+/// Logs a subscriber can fall behind by before it skips ahead, so a slow
+/// client never holds up ingestion.
+const CAPACITY: usize = 1024;
+
+static CHANNEL: OnceLock<broadcast::Sender<Arc<Tailed>>> = OnceLock::new();
+
+/// A log as it is written, with the app it was written to.
+#[derive(Debug)]
+pub struct Tailed {
+    pub app: String,
+    pub log: Log,
+}
+
+pub enum Event {
+    Log(Arc<Tailed>),
+    /// The subscriber fell behind and missed this many logs, those of other
+    /// apps included.
+    Lagged(u64),
+}
+
+fn channel() -> &'static broadcast::Sender<Arc<Tailed>> {
+    CHANNEL.get_or_init(|| broadcast::channel(CAPACITY).0)
+}
+
+pub fn subscribe() -> broadcast::Receiver<Arc<Tailed>> {
+    channel().subscribe()
+}
+
+/// Sends a log that was just written to everyone tailing its app.
+pub fn publish(app: &str, log: &Log) {
+    let channel = channel();
+    if channel.receiver_count() == 0 {
+        return;
+    }
+    let _ = channel.send(Arc::new(Tailed {
+        app: app.to_string(),
+        log: log.clone(),
+    }));
+}
+
+/// Waits for the next log of the app matching the filter. Returns `None` once
+/// the channel is closed.
+pub async fn next(
+    receiver: &mut broadcast::Receiver<Arc<Tailed>>,
+    app: &str,
+    filter: &Filter,
+) -> Option<Event> {
+    loop {
+        match receiver.recv().await {
+            Ok(tailed) if tailed.app == app && filter.matches(&tailed.log) => {
+                return Some(Event::Log(tailed))
+            }
+            Ok(_) => continue,
+            Err(RecvError::Lagged(missed)) => return Some(Event::Lagged(missed)),
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// Sends logs as `log` events with the log as JSON data, and `lagged` events
+/// with the number of missed logs when the client falls behind.
+pub fn sse(
+    receiver: broadcast::Receiver<Arc<Tailed>>,
+    app: String,
+    filter: Filter,
+) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    let events = stream::unfold(
+        (receiver, app, filter),
+        |(mut receiver, app, filter)| async move {
+            let event = match next(&mut receiver, &app, &filter).await? {
+                Event::Log(tailed) => SseEvent::default()
+                    .event("log")
+                    .json_data(&tailed.log)
+                    .unwrap(),
+                Event::Lagged(missed) => {
+                    SseEvent::default().event("lagged").data(missed.to_string())
+                }
+            };
+            Some((Ok(event), (receiver, app, filter)))
+        },
+    );
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// Sends logs as JSON text messages, and `{"lagged":<missed>}` when the
+/// client falls behind, until the client closes the socket.
+pub async fn websocket(
+    mut socket: WebSocket,
+    mut receiver: broadcast::Receiver<Arc<Tailed>>,
+    app: String,
+    filter: Filter,
+) {
+    loop {
+        tokio::select! {
+            event = next(&mut receiver, &app, &filter) => {
+                let message = match event {
+                    Some(Event::Log(tailed)) => serde_json::to_string(&tailed.log).unwrap(),
+                    Some(Event::Lagged(missed)) => json!({ "lagged": missed }).to_string(),
+                    None => break,
+                };
+                if socket.send(Message::Text(message)).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::empty_log;
+    use crate::ErrorLevel;
+
+    fn tailed(app: &str, level: ErrorLevel) -> Arc<Tailed> {
+        let mut log = empty_log();
+        log.level = level;
+        Arc::new(Tailed {
+            app: app.to_string(),
+            log,
+        })
+    }
+
+    #[tokio::test]
+    async fn skips_other_apps_and_reports_lag() {
+        let (sender, mut receiver) = broadcast::channel(2);
+        let filter = Filter {
+            levels: vec![ErrorLevel::Error],
+            ..Filter::default()
+        };
+
+        sender.send(tailed("blog", ErrorLevel::Error)).unwrap();
+        sender.send(tailed("shop", ErrorLevel::Info)).unwrap();
+        let expected = tailed("shop", ErrorLevel::Error);
+        sender.send(expected.clone()).unwrap();
+        // The oldest log was dropped for the third.
+        assert!(matches!(
+            next(&mut receiver, "shop", &filter).await,
+            Some(Event::Lagged(1))
+        ));
+        match next(&mut receiver, "shop", &filter).await {
+            Some(Event::Log(tailed)) => assert_eq!(tailed.log.uid, expected.log.uid),
+            _ => panic!("expected a log"),
+        }
+
+        drop(sender);
+        assert!(next(&mut receiver, "shop", &filter).await.is_none());
+    }
+}
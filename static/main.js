@@ -887,7 +887,7 @@ class MessageSearch extends HTMLElement {
         if (req.ok){
             const res = await req.json();
             console.log("Search results", res);
-            window.dispatchEvent(new CustomEvent("search-results", { detail: res }));
+            window.dispatchEvent(new CustomEvent("search-results", { detail: res.map(log => log.uid) }));
         }
     }, 600);
 }